@@ -31,7 +31,8 @@
 //!
 //! * `path` (as `path = "foo.html"`): sets the path to the template file. The
 //!   path is interpreted as relative to the `templates` dir in the directory
-//!   where the originating crate's `Cargo.toml` resides. The file name
+//!   where the originating crate's `Cargo.toml` resides, or to the template
+//!   directories configured in `askama.toml` (see below). The file name
 //!   extension is used to infer an escape mode (see below). In web framework
 //!   integrations, the path's extension may also be used to infer the content
 //!   type of the resulting response. Cannot be used together with `source`.
@@ -50,15 +51,77 @@
 //! * `escape` (as `escape = "none"`): change escape mode for expression
 //!   output. By default, Askama infers the escape mode from the template
 //!   file name (with `path`) or specified extension (`ext`): if the extension
-//!   is `html`, `htm` or `xml`, the `html` escape mode is used; otherwise,
+//!   is `html`, `htm` or `xml` (or another extension configured in
+//!   `askama.toml`), the `html` escape mode is used; otherwise,
 //!   no implicit escaping is done. The escape mode can be overridden by
 //!   specifying it manually, enabling it with `escape = "html"` or disabling
 //!   with `escape = "none"`. The `html` escape mode escapes content according
 //!   to the [OWASP escaping recommendations][owasp].
+//! * `syntax` (as `syntax = "latex"`): set the delimiters used by the
+//!   template to a custom syntax defined in the crate's `askama.toml` (see
+//!   below). Without this key, the crate's default syntax is used.
+//! * `dirs` (as `dirs("overrides", "templates")`): set the template
+//!   directories for this template, relative to the crate root, in place of
+//!   the ones configured in `askama.toml`.
+//! * `parent` (as `parent = "base"`): the name of the field holding the
+//!   context of the template extended by this one (see *Template inheritance*
+//!   below). Defaults to `_parent`.
 //!
 //! [owasp]: https://www.owasp.org/index.php/XSS_(Cross_Site_Scripting)_Prevention_Cheat_Sheet#RULE_.231_-_HTML_Escape_Before_Inserting_Untrusted_Data_into_HTML_Element_Content
 //!
 //!
+//! ## Configuration
+//!
+//! Settings that apply to all templates in a crate can be put in an
+//! `askama.toml` file next to the crate's `Cargo.toml`. All settings are
+//! optional:
+//!
+//! ```toml
+//! [general]
+//! # Directories to look for templates in, relative to the crate root
+//! dirs = ["templates", "../shared/templates"]
+//! # Set to "suppress" to handle whitespace around every block and
+//! # expression as if it was marked with `-`
+//! whitespace = "preserve"
+//! # The syntax used by templates without a `syntax` key
+//! default_syntax = "default"
+//!
+//! # Escape mode ("html" or "none") for templates with these extensions
+//! [[escaper]]
+//! mode = "html"
+//! extensions = ["svg"]
+//! ```
+//!
+//! Templates named in `path`, `extends` and `include` are looked up in each
+//! of the template directories in order, and only then next to the template
+//! that refers to them (if any); the first match is used. This lets a
+//! directory listed earlier override templates that are used by templates
+//! in a later one. If no match is found, the error lists all directories
+//! that were searched.
+//!
+//! ## Custom syntax
+//!
+//! When the default `{% %}`, `{{ }}` and `{# #}` delimiters clash with the
+//! content of a template, a different syntax can be defined in
+//! `askama.toml`, and selected for a template with the `syntax` key or for
+//! the whole crate with the `default_syntax` setting:
+//!
+//! ```toml
+//! [[syntax]]
+//! name = "latex"
+//! block_start = "\\BLOCK{"
+//! block_end = "}"
+//! expr_start = "\\VAR{"
+//! expr_end = "}"
+//! comment_start = "\\#{"
+//! comment_end = "}"
+//! ```
+//!
+//! Delimiters that are left out keep their default value. The built-in
+//! syntax is always available under the name `default`. Templates pulled in
+//! with `include` are parsed with the same syntax as the template that
+//! includes them.
+//!
 //! ## Variables
 //!
 //! Top-level template variables are defined by the template's context type.
@@ -69,6 +132,14 @@
 //! while `{{ user.name }}` will get the ``name`` field of the ``user``
 //! field from the template context.
 //!
+//! Local variables can be assigned with `{% let name = user.name %}`. The
+//! left-hand side may also be a tuple or struct pattern, as in
+//! `{% let (first, last) = user.names() %}`, in which case the value is
+//! borrowed and each name is bound to a reference into it. Unlike the
+//! patterns of `match` arms, the patterns of `let` and `for` only bind
+//! names, so they can't contain literals or plain paths; a name starting
+//! with an uppercase letter, like `Foo`, counts as a path.
+//!
 //! ## Filters
 //!
 //! Values such as those obtained from variables can be post-processed
@@ -95,6 +166,19 @@
 //! Askama considers all tabs, spaces, newlines and carriage returns to be
 //! whitespace.
 //!
+//! ## Raw blocks
+//!
+//! Everything between `{% raw %}` and `{% endraw %}` is written to the
+//! output as-is, so that template syntax meant for another tool (such as a
+//! client-side JavaScript framework) can be embedded in a template:
+//!
+//! ```text
+//! {% raw %}<li>{{ item.name }}</li>{% endraw %}
+//! ```
+//!
+//! Whitespace around the content can be suppressed with minus signs in the
+//! `raw` and `endraw` tags, as with any other block.
+//!
 //! ## Template inheritance
 //!
 //! Template inheritance allows you to build a base template with common
@@ -121,8 +205,11 @@
 //! The `block` tags define three blocks that can be filled in by child
 //! templates. The base template defines a default version of the block.
 //! A base template must define one or more blocks in order to be enable
-//! inheritance. Blocks can only be specified at the top level of a template,
-//! not inside `if`/`else` branches or in `for`-loop bodies.
+//! inheritance. Blocks can be nested inside other blocks, `if`/`else`
+//! branches, `match` arms and `for`-loop bodies, where they can refer to the
+//! variables in scope (like the loop variable). A child template can override
+//! any of them; which definition is used does not depend on how the template
+//! is rendered. Blocks are not allowed in included templates or in macros.
 //!
 //! ### Child template
 //!
@@ -145,18 +232,39 @@
 //! ```
 //!
 //! The `extends` tag tells the code generator that this template inherits
-//! from another template. It will search for the base template relative to
-//! itself before looking relative to the template base directory. It will
-//! render the top-level content from the base template, and substitute
-//! blocks from the base template with those from the child template. The
-//! inheriting template context `struct` must have a field called `_parent` of
-//! the type used as the base template context. Blocks can refer to the context
-//! of both parent and child template.
-//!
-//! Note that, if the base template lives in another module than the child
-//! template, the child template's module should import all symbols from the
-//! base template's module in order for it to find the trait definition that
-//! supports the inheritance mechanism.
+//! from another template. It will search for the base template in the
+//! template directories before looking relative to the template itself. It
+//! will render the top-level content from the base template, and substitute
+//! blocks from the base template with those from the child template.
+//!
+//! The code generated for the child template renders the base template's
+//! content as well, so the child's context `struct` has to provide every
+//! field that the base template uses. It can simply define those fields
+//! itself. Alternatively, it can hold an instance of the base template's
+//! context type in a field called `_parent`. A `Deref` implementation to
+//! that field is then generated, so that the base template's fields can be
+//! found through it. Another name for that field can be set with the
+//! `parent` key (as in `parent = "base"`) in the `template()` attribute.
+//! Blocks can refer to the context of both parent and child template.
+//!
+//! A base template can itself extend another template, so inheritance chains
+//! can be as deep as needed (as in `base.html`, `section.html` and
+//! `page.html`). Each context `struct` in the chain either provides the
+//! fields of all templates above it or holds the next one up in its parent
+//! field, and every block is rendered from the most derived template that
+//! defines it. Inside a block, `{{ super() }}` renders the version of that
+//! block from the next template up the chain that defines it:
+//!
+//! ```text
+//! {% block head %}
+//!   {{ super() }}
+//!   <link rel="stylesheet" href="page.css">
+//! {% endblock %}
+//! ```
+//!
+//! Since `super()` renders template content rather than producing a value,
+//! it has to be the whole expression; it can't be filtered or combined with
+//! anything else.
 //!
 //! ## HTML escaping
 //!
@@ -183,6 +291,68 @@
 //!
 //! * *loop.index*: current loop iteration (starting from 1)
 //! * *loop.index0*: current loop iteration (starting from 0)
+//! * *loop.first*: whether this is the first iteration of the loop
+//! * *loop.last*: whether this is the last iteration of the loop
+//! * *loop.length*: the number of items in the iterable
+//! * *loop.revindex*: number of iterations until the end (ending at 1)
+//! * *loop.revindex0*: number of iterations until the end (ending at 0)
+//! * *loop.cycle(...)*: cycles through its arguments, so that
+//!   `{{ loop.cycle("odd", "even") }}` alternates between the two values
+//!
+//! The `length`, `revindex` and `revindex0` variables can only be used if
+//! the iterator implements `ExactSizeIterator`, which is the case for the
+//! standard collections.
+//!
+//! An optional `else` block is rendered instead of the loop body if the
+//! iterable turns out to be empty:
+//!
+//! ```text
+//! {% for user in users %}
+//!   <li>{{ user.name|e }}</li>
+//! {% else %}
+//!   <li>No users</li>
+//! {% endfor %}
+//! ```
+//!
+//! Inside a loop body, `{% break %}` stops the loop and `{% continue %}`
+//! skips to the next item. Both are only allowed inside a `for` loop, and
+//! since nothing after them is rendered, they must be the last tag in their
+//! block, such as an `if` branch.
+//!
+//! Items can be filtered by adding a condition to the `for` tag. The loop
+//! variables then describe the filtered sequence, so `loop.index` and
+//! `loop.length` only count the items that are rendered:
+//!
+//! ```text
+//! {% for user in users if user.active %}
+//!   <li>{{ loop.index }}. {{ user.name|e }}</li>
+//! {% endfor %}
+//! ```
+//!
+//! The loop variable can also be a pattern that destructures each item,
+//! like tuples (`{% for (key, value) in map %}`) or structs
+//! (`{% for Point { x, y } in points %}`), nested as deeply as needed.
+//!
+//! Nested data like trees can be rendered with a recursive loop. Inside
+//! a loop marked `recursive`, `{{ loop(children) }}` renders the loop body
+//! again for each item in `children`, which must have the same type as the
+//! loop's iterable:
+//!
+//! ```text
+//! <ul>
+//! {% for item in sitemap recursive %}
+//!   <li>{{ item.title }}
+//!   {% if !item.children.is_empty() %}
+//!     <ul>{{ loop(item.children) }}</ul>
+//!   {% endif %}
+//!   </li>
+//! {% endfor %}
+//! </ul>
+//! ```
+//!
+//! In a recursive loop, *loop.depth* and *loop.depth0* give the level of
+//! the current item, starting from 1 and 0 respectively. Macros cannot call
+//! themselves, so a recursive loop is the way to render nested data.
 //!
 //! ### If
 //!
@@ -198,6 +368,34 @@
 //! {% endif %}
 //! ```
 //!
+//! ### Match
+//!
+//! In order to deal with Rust `enum`s in a type-safe way, templates support
+//! match blocks. Here is a simple example showing how to
+//! expand an `Option`:
+//!
+//! ```text
+//! {% match item %}
+//!   {% when Some("foo") %}
+//!     Found literal foo
+//!   {% when Some(val) %}
+//!     Found {{ val }}
+//!   {% when None %}
+//! {% endmatch %}
+//! ```
+//!
+//! Each `when` arm takes a Rust pattern: enum variant paths like
+//! `Color::Transparent`, tuple patterns like `Some(val)`, struct patterns
+//! like `Color::Named { name, dark: true }`, string and integer literals,
+//! or `_` as a wildcard. Names in a pattern are bound as local variables
+//! for the arm's body; a single name starting with an uppercase letter is
+//! taken to be a path (like `None`) rather than a binding.
+//!
+//! The matched expression is borrowed, and the generated code is a plain
+//! Rust `match`, so the compiler checks that the arms are exhaustive.
+//! Only whitespace is allowed between the `match` tag and the first `when`
+//! arm, and it is not rendered.
+//!
 //! ### Include
 //!
 //! The *include* statement lets you split large or repetitive blocks into
@@ -215,18 +413,134 @@
 //! ```
 //!
 //! The path to include must be a string literal, so that it is known at
-//! compile time. Askama will try to find the specified template relative
-//! to the including template's path before falling back to the absolute
-//! template path. Use `include` within the branches of an `if`/`else`
-//! block to use includes more dynamically.
+//! compile time. Askama will look for the specified template in each of the
+//! template directories in order, before falling back to the including
+//! template's own directory. Use `include` within the branches of an
+//! `if`/`else` block to use includes more dynamically.
+//!
+//! To give an included template only a limited set of names, list them
+//! in a `with` context. Each entry binds a name to an expression evaluated
+//! where the `include` is; a lone name like `compact` is short for
+//! `compact: compact`. The caller's other local variables are then not
+//! visible in the included template, while the template's fields still are:
+//!
+//! ```text
+//! {% for user in users %}
+//!   {% include "row.html" with { item: user, last: loop.last } %}
+//! {% endfor %}
+//! ```
+//!
+//! The enclosing loop isn't visible either, so the included template can't
+//! use `loop` variables or `break` and `continue`; pass the values it needs,
+//! like `last` above, in the context instead.
+//!
+//! Adding `ignore missing` after the path renders nothing if the template
+//! can't be found, instead of failing the build. Note that a template that
+//! was missing is only picked up once the including template is rebuilt:
+//!
+//! ```text
+//! {% include "theme/footer.html" ignore missing %}
+//! ```
+//!
+//! ## Macros
+//!
+//! Macros are reusable pieces of template code with parameters. They are
+//! defined at the top level of a template and expanded with `call`:
+//!
+//! ```text
+//! {% macro heading(text) %}<h1>{{ text }}</h1>{% endmacro %}
+//!
+//! {% call heading(title) %}
+//! ```
+//!
+//! Parameters can have a default value, which is used when the call doesn't
+//! provide an argument for them. Arguments can be given by position or by
+//! name, with positional arguments coming first:
+//!
+//! ```text
+//! {% macro button(label, kind = "primary") %}
+//!   <button class="{{ kind }}">{{ label }}</button>
+//! {% endmacro %}
+//!
+//! {% call button("Save") %}
+//! {% call button(kind = "danger", label = "Delete") %}
+//! ```
+//!
+//! Calling a macro with too many arguments, with unknown names, or without
+//! a value for every parameter that has no default is a compile error.
+//!
+//! Default values are part of the macro definition: they can use the
+//! parameters before them and the template's fields, but not the local
+//! variables of the place the macro is called from.
+//!
+//! A `callblock` tag, closed by `endcallblock`, calls a macro like `call`
+//! does and passes its body to the macro, which renders it wherever it calls
+//! `caller()`. The body can use the variables in scope where the macro is
+//! called, except those that the macro itself defines (like its parameters),
+//! which take precedence. Likewise, `loop` in the body refers to the `for`
+//! loop around the `callblock` tag, even where the macro renders the body
+//! from a loop of its own; `break` and `continue` can't be used to leave
+//! that loop from the body, though. The body can also declare parameters of
+//! its own, which the macro passes to `caller()`:
+//!
+//! ```text
+//! {% macro card(title) %}
+//!   <div class="card"><h2>{{ title }}</h2>{{ caller() }}</div>
+//! {% endmacro %}
+//! {% macro list(items) %}
+//!   <ul>{% for item in items %}<li>{{ caller(item) }}</li>{% endfor %}</ul>
+//! {% endmacro %}
+//!
+//! {% callblock card("Profile") %}<p>{{ user.name }}</p>{% endcallblock %}
+//! {% callblock(entry) list(entries) %}{{ entry.title }}{% endcallblock %}
+//! ```
+//!
+//! Macros defined in another template file can be imported into a namespace
+//! with an `import` tag at the top level of a template, and called through
+//! that namespace, either with `call` or as an expression:
+//!
+//! ```text
+//! {% import "forms.html" as forms %}
+//!
+//! {% call forms::input("email") %}
+//! {{ forms::input("password") }}
+//! ```
+//!
+//! The path is looked up in the same way as for `include`. Each imported
+//! file is only parsed once, however often its macros are called. Macros
+//! from different imports don't clash, and a macro can call the other
+//! macros of the file that defines it without a namespace. When an import
+//! has the same name as a Rust module, `{{ forms::input() }}` calls the
+//! imported macro rather than a function from that module.
 //!
 //! ## Expressions
 //!
-//! Askama supports string literals (`"foo"`) and integer literals (`1`).
+//! Askama supports string literals (`"foo"`), character literals (`'c'`),
+//! integer and floating point literals (`1`, `3.14`) and the boolean
+//! literals `true` and `false`.
+//! String and character literals use the same escape sequences as Rust
+//! (`"a \"quoted\" word\n"`), and raw strings like `r#"say "hi""#` are
+//! supported as well. Strings can also be single-quoted, as in `'it\'s'`;
+//! a single-quoted literal that contains exactly one character is a
+//! character literal.
 //! It supports almost all binary operators that Rust supports,
-//! including arithmetic, comparison and logic operators.
+//! including arithmetic, comparison and logic operators, as well as
+//! the unary `!` (negation) and `-` (as in `-1` or `-total`) operators.
 //! The parser applies the same precedence order as the Rust compiler.
 //! Expressions can be grouped using parentheses.
+//! Array literals (`["S", "M", "L"]`) and tuple literals (`("a", 1)`) are
+//! supported too, so a template can loop over a fixed list of values with
+//! `{% for size in ["S", "M", "L"] %}`.
+//!
+//! Values can be indexed with square brackets, as in `items[0]` or
+//! `map["key"]`, and ranges are written as in Rust: `0..n`, `1..=3`, `..2`
+//! and `2..` are all supported. Ranges can be iterated over in a `for`
+//! loop or used to slice a value, as in `{{ name[1..3] }}`.
+//!
+//! Paths such as `Status::Active` or `self::MAX_ITEMS` refer to items in
+//! scope where the template struct is defined, and free functions can be
+//! called with the same syntax, as in `{{ helpers::format_price(price) }}`.
+//! Paths and function calls are emitted into the generated code verbatim.
 //! The HTML special characters `&`, `<` and `>` will be replaced with their
 //! character entities unless the `escape` mode is disabled for a template.
 //!
@@ -286,6 +600,7 @@ pub trait Template {
 }
 
 pub use shared::filters;
+pub use shared::helpers;
 pub use askama_derive::*;
 pub use shared::{Error, MarkupDisplay, Result};
 
@@ -334,15 +649,18 @@ fn visit_dirs(dir: &Path, cb: &Fn(&DirEntry)) -> io::Result<()> {
 
 /// Build script helper to rebuild crates if contained templates have changed
 ///
-/// Iterates over all files in the template dir (`templates` in
-/// `CARGO_MANIFEST_DIR`) and writes a `cargo:rerun-if-changed=` line for each
-/// of them to stdout.
+/// Iterates over all files in the template dirs (`templates` in
+/// `CARGO_MANIFEST_DIR`, unless configured otherwise in `askama.toml`) and
+/// writes a `cargo:rerun-if-changed=` line for each of them to stdout.
 ///
-/// This helper method can be used in build scripts (`build.rs`) in crates
-/// that have templates, to make sure the crate gets rebuilt when template
-/// source code changes.
+/// This helper method used to be required in build scripts (`build.rs`) to
+/// make sure the crate gets rebuilt when template source code changes. The
+/// generated code now tells the compiler which template files it depends
+/// on, so calling it is no longer necessary.
 pub fn rerun_if_templates_changed() {
-    visit_dirs(&path::template_dir(), &|e: &DirEntry| {
-        println!("cargo:rerun-if-changed={}", e.path().to_str().unwrap());
-    }).unwrap();
+    for dir in path::template_dirs() {
+        visit_dirs(&dir, &|e: &DirEntry| {
+            println!("cargo:rerun-if-changed={}", e.path().to_str().unwrap());
+        }).unwrap();
+    }
 }
@@ -11,5 +11,8 @@ pub fn derive_template(input: TokenStream) -> TokenStream {
         syn::Body::Struct(ref data) => data,
         _ => panic!("#[derive(Template)] can only be used with structs"),
     };
-    shared::build_template(&ast).parse().unwrap()
+    match shared::build_template(&ast) {
+        Ok(code) => code,
+        Err(err) => format!("compile_error!({:?});", err.to_string()),
+    }.parse().unwrap()
 }
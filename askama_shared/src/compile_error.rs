@@ -0,0 +1,116 @@
+use std::fmt;
+use std::path::Path;
+
+/// An error found while parsing a template or generating code for it
+///
+/// When the offending part of the template is known, the error carries its
+/// file, line and column, and the `Display` implementation renders the
+/// source line with a caret pointing at the problem.
+#[derive(Debug)]
+pub struct CompileError {
+    msg: String,
+    location: Option<Location>,
+}
+
+impl CompileError {
+    pub fn new<T: Into<String>>(msg: T) -> CompileError {
+        CompileError { msg: msg.into(), location: None }
+    }
+
+    // Creates an error pointing at `fragment`, which must be a slice of
+    // the source in `file` for the location to be found.
+    pub fn at<T: Into<String>>(msg: T, file: &FileInfo, fragment: &str) -> CompileError {
+        CompileError { msg: msg.into(), location: file.locate(fragment) }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.msg)?;
+        let loc = match self.location {
+            Some(ref loc) => loc,
+            None => return Ok(()),
+        };
+
+        let line_no = loc.line.to_string();
+        let gutter = " ".repeat(line_no.len());
+        let marker: String = loc.text.chars().take(loc.column - 1).map(|c| {
+            if c == '\t' { '\t' } else { ' ' }
+        }).collect();
+        write!(f, "\n{}--> {}:{}:{}", gutter, loc.file, loc.line, loc.column)?;
+        write!(f, "\n{} |", gutter)?;
+        write!(f, "\n{} | {}", line_no, loc.text)?;
+        write!(f, "\n{} | {}^", gutter, marker)
+    }
+}
+
+#[derive(Debug)]
+struct Location {
+    file: String,
+    line: usize,
+    column: usize,
+    text: String,
+}
+
+/// The template file that parse tree fragments are borrowed from
+///
+/// Nodes and expressions hold slices into the template source, so the
+/// position of a fragment can be recovered from its address.
+#[derive(Clone, Copy)]
+pub struct FileInfo<'a> {
+    pub path: Option<&'a Path>,
+    pub source: &'a str,
+}
+
+impl<'a> FileInfo<'a> {
+    fn locate(&self, fragment: &str) -> Option<Location> {
+        let start = self.source.as_ptr() as usize;
+        let pos = fragment.as_ptr() as usize;
+        if pos < start || pos > start + self.source.len() {
+            return None;
+        }
+
+        let offset = pos - start;
+        let before = &self.source[..offset];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = self.source[offset..].find('\n')
+            .map(|i| offset + i)
+            .unwrap_or_else(|| self.source.len());
+        Some(Location {
+            file: match self.path {
+                Some(path) => path.display().to_string(),
+                None => "<source>".to_string(),
+            },
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            text: self.source[line_start..line_end].replace('\r', ""),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompileError, FileInfo};
+    use std::path::Path;
+
+    #[test]
+    fn test_location() {
+        let src = "foo\n  {{ bar }}\nbaz";
+        let file = FileInfo { path: Some(Path::new("test.html")), source: src };
+        let err = CompileError::at("unknown thing", &file, &src[9..]);
+        assert_eq!(err.to_string(),
+                   "unknown thing\n \
+                    --> test.html:2:6\n  \
+                    |\n\
+                    2 |   {{ bar }}\n  \
+                    |      ^");
+    }
+
+    #[test]
+    fn test_foreign_fragment() {
+        let other = String::from("foo");
+        let file = FileInfo { path: None, source: "{{ foo }}" };
+        let err = CompileError::at("unknown thing", &file, &other);
+        assert_eq!(err.to_string(), "unknown thing");
+    }
+}
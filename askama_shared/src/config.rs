@@ -0,0 +1,304 @@
+use compile_error::CompileError;
+use input::EscapeMode;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+
+/// Crate-wide settings, read from `askama.toml` in the crate root
+///
+/// All settings are optional, so a crate without a configuration file
+/// gets the same behavior as one with an empty file.
+pub struct Config {
+    // The configuration file, if the crate has one
+    pub file: Option<PathBuf>,
+    pub dirs: Vec<PathBuf>,
+    pub syntaxes: BTreeMap<String, Syntax>,
+    pub default_syntax: String,
+    pub escapers: BTreeMap<String, EscapeMode>,
+    pub whitespace: Whitespace,
+}
+
+// Returns the directory containing the `Cargo.toml` of the crate being built.
+pub fn manifest_dir() -> Result<PathBuf, CompileError> {
+    env::var("CARGO_MANIFEST_DIR").map(PathBuf::from).map_err(|_| CompileError::new(
+        "CARGO_MANIFEST_DIR is not set, templates must be built with Cargo"))
+}
+
+impl Config {
+    pub fn new() -> Result<Config, CompileError> {
+        let root = manifest_dir()?;
+        let path = root.join(CONFIG_FILE_NAME);
+        if !path.exists() {
+            return Config::from_toml("", &root);
+        }
+        let mut s = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut s))
+            .map_err(|err| CompileError::new(format!(
+                "unable to read '{}': {}", path.display(), err)))?;
+        let mut config = Config::from_toml(&s, &root)?;
+        config.file = Some(path);
+        Ok(config)
+    }
+
+    // Relative template directories are resolved against `root`, which is
+    // the directory containing the crate's `Cargo.toml`.
+    pub fn from_toml(s: &str, root: &Path) -> Result<Config, CompileError> {
+        let raw: RawConfig = toml::from_str(s).map_err(|err| {
+            CompileError::new(format!("invalid {}: {}", CONFIG_FILE_NAME, err))
+        })?;
+        let general = raw.general.unwrap_or_default();
+
+        let dirs = general.dirs
+            .unwrap_or_else(|| vec![DEFAULT_TEMPLATE_DIR.to_string()])
+            .iter()
+            .map(|dir| root.join(dir))
+            .collect::<Vec<_>>();
+        if dirs.is_empty() {
+            return Err(CompileError::new("at least one template directory is required"));
+        }
+
+        let mut syntaxes = BTreeMap::new();
+        syntaxes.insert(DEFAULT_SYNTAX_NAME.to_string(), Syntax::default());
+        for raw_syntax in raw.syntax.unwrap_or_default() {
+            let name = raw_syntax.name.clone();
+            if syntaxes.contains_key(&name) {
+                return Err(CompileError::new(format!(
+                    "syntax '{}' is already defined", name)));
+            }
+            syntaxes.insert(name, Syntax::from_raw(raw_syntax)?);
+        }
+
+        let default_syntax = general.default_syntax
+            .unwrap_or_else(|| DEFAULT_SYNTAX_NAME.to_string());
+        if !syntaxes.contains_key(&default_syntax) {
+            return Err(CompileError::new(format!(
+                "default syntax '{}' not found", default_syntax)));
+        }
+
+        let mut escapers = BTreeMap::new();
+        for ext in &HTML_EXTENSIONS {
+            escapers.insert(ext.to_string(), EscapeMode::Html);
+        }
+        for escaper in raw.escaper.unwrap_or_default() {
+            let mode = match escaper.mode.as_str() {
+                "html" => EscapeMode::Html,
+                "none" => EscapeMode::None,
+                v => return Err(CompileError::new(format!("invalid escape mode '{}'", v))),
+            };
+            for ext in escaper.extensions {
+                escapers.insert(ext, mode);
+            }
+        }
+
+        let whitespace = match general.whitespace.as_ref().map(|s| s.as_str()) {
+            None | Some("preserve") => Whitespace::Preserve,
+            Some("suppress") => Whitespace::Suppress,
+            Some(v) => return Err(CompileError::new(format!(
+                "invalid whitespace handling '{}'", v))),
+        };
+
+        Ok(Config { file: None, dirs, syntaxes, default_syntax, escapers, whitespace })
+    }
+
+    // Returns the escape mode for templates with the given file extension.
+    pub fn escaping_for(&self, ext: &str) -> EscapeMode {
+        self.escapers.get(ext).cloned().unwrap_or(EscapeMode::None)
+    }
+
+    // Looks up the syntax with the given name, or the crate's default
+    // syntax if no name was given.
+    pub fn find_syntax(&self, name: Option<&str>) -> Result<&Syntax, CompileError> {
+        let name = name.unwrap_or(&self.default_syntax);
+        self.syntaxes.get(name).ok_or_else(|| {
+            CompileError::new(format!("syntax '{}' not found", name))
+        })
+    }
+}
+
+/// How whitespace around blocks and expressions is handled by default
+///
+/// With `Suppress`, every tag behaves as if it was written with the `-`
+/// whitespace markers on both sides.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Whitespace {
+    Preserve,
+    Suppress,
+}
+
+/// The delimiters used for blocks, expressions and comments in a template
+pub struct Syntax {
+    pub block_start: String,
+    pub block_end: String,
+    pub expr_start: String,
+    pub expr_end: String,
+    pub comment_start: String,
+    pub comment_end: String,
+}
+
+impl Default for Syntax {
+    fn default() -> Syntax {
+        Syntax {
+            block_start: "{%".to_string(),
+            block_end: "%}".to_string(),
+            expr_start: "{{".to_string(),
+            expr_end: "}}".to_string(),
+            comment_start: "{#".to_string(),
+            comment_end: "#}".to_string(),
+        }
+    }
+}
+
+impl Syntax {
+    fn from_raw(raw: RawSyntax) -> Result<Syntax, CompileError> {
+        let default = Syntax::default();
+        let syntax = Syntax {
+            block_start: raw.block_start.unwrap_or(default.block_start),
+            block_end: raw.block_end.unwrap_or(default.block_end),
+            expr_start: raw.expr_start.unwrap_or(default.expr_start),
+            expr_end: raw.expr_end.unwrap_or(default.expr_end),
+            comment_start: raw.comment_start.unwrap_or(default.comment_start),
+            comment_end: raw.comment_end.unwrap_or(default.comment_end),
+        };
+
+        let delimiters = [
+            &syntax.block_start, &syntax.block_end,
+            &syntax.expr_start, &syntax.expr_end,
+            &syntax.comment_start, &syntax.comment_end,
+        ];
+        if delimiters.iter().any(|s| s.is_empty()) {
+            return Err(CompileError::new(format!(
+                "delimiters in syntax '{}' must not be empty", raw.name)));
+        }
+        if syntax.block_start == syntax.expr_start ||
+                syntax.block_start == syntax.comment_start ||
+                syntax.expr_start == syntax.comment_start {
+            return Err(CompileError::new(format!(
+                "start delimiters in syntax '{}' must be distinct", raw.name)));
+        }
+        Ok(syntax)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    general: Option<RawGeneral>,
+    syntax: Option<Vec<RawSyntax>>,
+    escaper: Option<Vec<RawEscaper>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawGeneral {
+    dirs: Option<Vec<String>>,
+    default_syntax: Option<String>,
+    whitespace: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSyntax {
+    name: String,
+    block_start: Option<String>,
+    block_end: Option<String>,
+    expr_start: Option<String>,
+    expr_end: Option<String>,
+    comment_start: Option<String>,
+    comment_end: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEscaper {
+    mode: String,
+    extensions: Vec<String>,
+}
+
+static CONFIG_FILE_NAME: &str = "askama.toml";
+static DEFAULT_TEMPLATE_DIR: &str = "templates";
+static DEFAULT_SYNTAX_NAME: &str = "default";
+const HTML_EXTENSIONS: [&str; 3] = ["html", "htm", "xml"];
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Whitespace};
+    use input::EscapeMode;
+    use std::path::Path;
+
+    #[test]
+    fn test_default_config() {
+        let config = Config::from_toml("", Path::new("/crate")).unwrap();
+        assert_eq!(config.dirs, vec![Path::new("/crate/templates")]);
+        assert!(config.escaping_for("html") == EscapeMode::Html);
+        assert!(config.escaping_for("txt") == EscapeMode::None);
+        assert!(config.whitespace == Whitespace::Preserve);
+        assert_eq!(config.default_syntax, "default");
+        let syntax = config.find_syntax(None).unwrap();
+        assert_eq!(syntax.block_start, "{%");
+        assert_eq!(syntax.comment_end, "#}");
+    }
+
+    #[test]
+    fn test_custom_syntax() {
+        let config = Config::from_toml(r#"
+            [general]
+            default_syntax = "latex"
+
+            [[syntax]]
+            name = "latex"
+            block_start = "<%"
+            block_end = "%>"
+            expr_start = "<<"
+            expr_end = ">>"
+        "#, Path::new("/crate")).unwrap();
+        let syntax = config.find_syntax(None).unwrap();
+        assert_eq!(syntax.block_start, "<%");
+        assert_eq!(syntax.expr_end, ">>");
+        assert_eq!(syntax.comment_start, "{#");
+        assert_eq!(config.find_syntax(Some("default")).unwrap().block_start, "{%");
+        assert!(config.find_syntax(Some("vue")).is_err());
+    }
+
+    #[test]
+    fn test_general() {
+        let config = Config::from_toml(r#"
+            [general]
+            dirs = ["templates", "../shared/templates"]
+            whitespace = "suppress"
+
+            [[escaper]]
+            mode = "html"
+            extensions = ["svg"]
+
+            [[escaper]]
+            mode = "none"
+            extensions = ["xml"]
+        "#, Path::new("/crate")).unwrap();
+        assert_eq!(config.dirs, vec![Path::new("/crate/templates"),
+                                     Path::new("/crate/../shared/templates")]);
+        assert!(config.whitespace == Whitespace::Suppress);
+        assert!(config.escaping_for("svg") == EscapeMode::Html);
+        assert!(config.escaping_for("htm") == EscapeMode::Html);
+        assert!(config.escaping_for("xml") == EscapeMode::None);
+    }
+
+    #[test]
+    fn test_invalid_syntax() {
+        assert!(Config::from_toml(r#"
+            [[syntax]]
+            name = "broken"
+            expr_start = "{%"
+        "#, Path::new("/crate")).is_err());
+        assert!(Config::from_toml(r#"
+            [general]
+            default_syntax = "missing"
+        "#, Path::new("/crate")).is_err());
+    }
+}
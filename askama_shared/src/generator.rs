@@ -1,89 +1,344 @@
+use compile_error::{CompileError, FileInfo};
+use config::{Syntax, Whitespace};
 use filters;
 use input::TemplateInput;
-use parser::{self, Cond, Expr, Macro, Node, Target, WS};
+use parser::{self, Cond, Expr, Loop, Macro, Node, Target, When, WS};
 use path;
 
 use quote::{Tokens, ToTokens};
 
-use std::{cmp, hash, str};
-use std::path::Path;
+use std::{cmp, hash, ptr, str};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::path::{Path, PathBuf};
 use std::collections::{HashMap, HashSet};
 
 use syn;
 
 
-pub fn generate(input: &TemplateInput, nodes: &[Node]) -> String {
-    Generator::default().build(&State::new(input, nodes))
+pub fn generate(input: &TemplateInput, nodes: &[Node]) -> Result<String, CompileError> {
+    // The code for a derived template renders the whole inheritance chain,
+    // and imported macros are expanded where they are called, so the
+    // templates this one extends or imports are loaded here as well.
+    let mut imports = Vec::new();
+    let parent = find_references(&input.file_info(), nodes, &input.dirs, &mut imports)?;
+    load_templates(input, parent, imports, Vec::new(), Vec::new(), &|ancestors, imports| {
+        let state = State::new(input, nodes, ancestors, imports)?;
+        let root = state.contexts.last().unwrap().file;
+        Generator::new(SetChain::new(), 0, root, input.config.whitespace).build(&state)
+    })
+}
+
+// A template that the derived template extends, or imports macros from
+struct Template<'a> {
+    path: &'a Path,
+    source: &'a str,
+    nodes: Vec<Node<'a>>,
+}
+
+impl<'a> Template<'a> {
+    fn file(&self) -> FileInfo<'a> {
+        FileInfo { path: Some(self.path), source: self.source }
+    }
+}
+
+// Loads the templates extended by the given one, from its parent up to the
+// root of the inheritance chain, and then the templates that macros are
+// imported from, directly or by other imported templates. Each template is
+// read and parsed once, by a call that keeps its source alive while the
+// next one is loaded; the last call passes them all to `build`.
+fn load_templates<'a>(input: &TemplateInput, parent: Option<PathBuf>,
+                      mut pending: Vec<PathBuf>,
+                      ancestors: Vec<Template<'a>>, imports: Vec<Template<'a>>,
+                      build: &Fn(&[Template], &[Template]) -> Result<String, CompileError>)
+                      -> Result<String, CompileError> {
+    let is_ancestor = parent.is_some();
+    let path = match parent {
+        Some(path) => {
+            if path == input.path || ancestors.iter().any(|t| t.path == path) {
+                return Err(CompileError::new(format!(
+                    "cyclic inheritance: '{}' extends itself", path.display())));
+            }
+            path
+        },
+        None => {
+            let mut next = None;
+            while let Some(path) = pending.pop() {
+                if !imports.iter().any(|t| t.path == path) {
+                    next = Some(path);
+                    break;
+                }
+            }
+            match next {
+                Some(path) => path,
+                None => return build(&ancestors, &imports),
+            }
+        },
+    };
+
+    let source = path::get_template_source(&path)?;
+    let nodes = parser::parse(&FileInfo { path: Some(&path), source: &source }, input.syntax)?;
+    let template = Template { path: &path, source: &source, nodes };
+    let parent = find_references(&template.file(), &template.nodes, &input.dirs,
+                                 &mut pending)?;
+    let (mut ancestors, mut imports): (Vec<Template>, Vec<Template>) = (ancestors, imports);
+    if is_ancestor {
+        ancestors.push(template);
+        load_templates(input, parent, pending, ancestors, imports, build)
+    } else {
+        imports.push(template);
+        load_templates(input, None, pending, ancestors, imports, build)
+    }
+}
+
+// Returns the path of the template named in the `extends` tag, if any, and
+// adds the paths of imported templates to `imports`.
+fn find_references(file: &FileInfo, nodes: &[Node], dirs: &[PathBuf],
+                   imports: &mut Vec<PathBuf>) -> Result<Option<PathBuf>, CompileError> {
+    let mut base = None;
+    for n in nodes {
+        match *n {
+            Node::Extends(Expr::StrLit(lit)) => {
+                if base.is_some() {
+                    return Err(CompileError::at("multiple extend blocks found", file, lit));
+                }
+                base = Some(lit);
+            },
+            Node::Import(_, lit, _) => {
+                imports.push(path::find_template_from_path(&str_lit_value(lit), file.path,
+                                                           dirs)?);
+            },
+            _ => {},
+        }
+    }
+    match base {
+        Some(lit) => Ok(Some(path::find_template_from_path(&str_lit_value(lit), file.path,
+                                                           dirs)?)),
+        None => Ok(None),
+    }
 }
 
 struct State<'a> {
     input: &'a TemplateInput<'a>,
-    nodes: &'a [Node<'a>],
-    blocks: Vec<&'a Node<'a>>,
-    macros: MacroMap<'a>,
-    trait_name: String,
-    derived: bool,
+    // The template followed by the ones it extends; the content of the last
+    // one (the root of the inheritance chain) is what gets rendered
+    contexts: Vec<Context<'a>>,
+    // All definitions of each block, from the most derived template up
+    blocks: HashMap<&'a str, Vec<(FileInfo<'a>, &'a Node<'a>)>>,
+    // The templates that macros are imported from
+    imports: Vec<FileInfo<'a>>,
+    // Macros visible in the template and its ancestors, followed by those
+    // visible in each of the imported templates
+    scopes: Vec<MacroScope<'a>>,
 }
 
 impl<'a> State<'a> {
-    fn new<'n>(input: &'n TemplateInput, nodes: &'n [Node]) -> State<'n> {
-        let mut base: Option<&Expr> = None;
-        let mut blocks = Vec::new();
-        let mut macros = HashMap::new();
-        for n in nodes.iter() {
-            match *n {
-                Node::Extends(ref path) => {
-                    match base {
-                        Some(_) => panic!("multiple extend blocks found"),
-                        None => { base = Some(path); },
+    fn new<'n>(input: &'n TemplateInput, nodes: &'n [Node],
+               ancestors: &'n [Template<'n>], imports: &'n [Template<'n>])
+               -> Result<State<'n>, CompileError> {
+        let mut contexts = vec![Context { file: input.file_info(), nodes }];
+        for template in ancestors {
+            contexts.push(Context { file: template.file(), nodes: &template.nodes });
+        }
+
+        let mut blocks = HashMap::new();
+        let mut scope = MacroScope::default();
+        for ctx in &contexts {
+            let mut defs = Vec::new();
+            find_blocks(ctx.nodes, &mut defs);
+            let mut seen = HashSet::new();
+            for def in defs {
+                if let Node::BlockDef(_, name, _, _) = *def {
+                    if !seen.insert(name) {
+                        return Err(CompileError::at(
+                            format!("block '{}' is defined more than once", name),
+                            &ctx.file, name));
                     }
-                },
-                ref def @ Node::BlockDef(_, _, _, _) => {
-                    blocks.push(def);
-                },
+                    blocks.entry(name).or_insert_with(Vec::new).push((ctx.file, def));
+                }
+            }
+            scope.add(&ctx.file, ctx.nodes, imports, &input.dirs)?;
+        }
+
+        let mut scopes = vec![scope];
+        let mut files = Vec::new();
+        for template in imports {
+            let file = template.file();
+            let mut scope = MacroScope::default();
+            scope.add(&file, &template.nodes, imports, &input.dirs)?;
+            scopes.push(scope);
+            files.push(file);
+        }
+        Ok(State { input, contexts, blocks, imports: files, scopes })
+    }
+
+    fn derived(&self) -> bool {
+        self.contexts.len() > 1
+    }
+}
+
+// Collects the block definitions in the given nodes, including those nested
+// in other blocks or in control structures.
+fn find_blocks<'a>(nodes: &'a [Node<'a>], defs: &mut Vec<&'a Node<'a>>) {
+    for n in nodes {
+        match *n {
+            Node::BlockDef(_, _, ref nodes, _) => {
+                defs.push(n);
+                find_blocks(nodes, defs);
+            },
+            Node::Cond(ref conds, _) => {
+                for &(_, _, ref nodes) in conds {
+                    find_blocks(nodes, defs);
+                }
+            },
+            Node::Loop(ref l) => {
+                find_blocks(&l.body, defs);
+                find_blocks(&l.else_block, defs);
+            },
+            Node::Match(_, _, ref arms, _) => {
+                for &(_, _, ref nodes) in arms {
+                    find_blocks(nodes, defs);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+// The macros that can be called from a template file, by name, and the
+// namespaces it imports as indices into `State::scopes`
+#[derive(Default)]
+struct MacroScope<'a> {
+    macros: MacroMap<'a>,
+    imports: HashMap<&'a str, usize>,
+}
+
+impl<'a> MacroScope<'a> {
+    // Adds the macros defined and imported in a file. Definitions that are
+    // already present take precedence, so more derived templates come first.
+    fn add(&mut self, file: &FileInfo<'a>, nodes: &'a [Node<'a>],
+           imports: &[Template], dirs: &[PathBuf]) -> Result<(), CompileError> {
+        for n in nodes {
+            match *n {
                 Node::Macro(name, ref m) => {
-                    macros.insert(name, m);
+                    self.macros.entry(name).or_insert((*file, m));
+                },
+                Node::Import(_, lit, scope) => {
+                    let path = path::find_template_from_path(&str_lit_value(lit), file.path,
+                                                             dirs)?;
+                    let idx = imports.iter().position(|t| t.path == path).unwrap();
+                    self.imports.entry(scope).or_insert(idx + 1);
                 },
                 _ => {},
             }
         }
-        State {
-            input,
-            nodes,
-            blocks,
-            macros,
-            trait_name: trait_name_for_path(&base, &input.path),
-            derived: base.is_some(),
-        }
+        Ok(())
     }
 }
 
-fn trait_name_for_path(base: &Option<&Expr>, path: &Path) -> String {
-    let rooted_path = match *base {
-        Some(&Expr::StrLit(user_path)) => {
-            path::find_template_from_path(user_path, Some(path))
-        },
-        _ => path.to_path_buf(),
-    };
+// A macro or the body of a `callblock` tag, with the file that defines it and
+// the index in `State::scopes` of the macros visible from it
+#[derive(Clone, Copy)]
+struct MacroRef<'a> {
+    file: FileInfo<'a>,
+    def: &'a Macro<'a>,
+    scope: usize,
+}
+
+// The body of a `callblock` tag, with the macros that were being expanded
+// and the `for` loop around it where the tag is. The macro's own loops hide
+// that loop's variables, so they are kept under names ending in `depth`.
+#[derive(Clone)]
+struct Caller<'a> {
+    body: MacroRef<'a>,
+    expanding: Vec<&'a Macro<'a>>,
+    in_loop: bool,
+    recursive_loop: bool,
+    depth: usize,
+    // Set if the body uses the length of the loop
+    needs_length: Rc<Cell<bool>>,
+}
+
+// A template file in the inheritance chain
+struct Context<'a> {
+    file: FileInfo<'a>,
+    nodes: &'a [Node<'a>],
+}
+
+// Returns the Rust code for a string literal as written in a template.
+// Double-quoted and raw strings are valid Rust as-is, single-quoted ones
+// need their double quotes escaped.
+fn rust_str_lit(lit: &str) -> String {
+    if !lit.starts_with('\'') {
+        return lit.to_string();
+    }
+    let mut res = String::from("\"");
+    let mut chars = lit[1..lit.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                res.push(c);
+                res.extend(chars.next());
+            },
+            '"' => res.push_str("\\\""),
+            _ => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
 
+// Returns the value of a string literal as written in a template, for
+// literals that are used at compile time such as template paths.
+fn str_lit_value(lit: &str) -> String {
+    if lit.starts_with('r') {
+        let hashes = lit[1..].find('"').unwrap();
+        return lit[hashes + 2..lit.len() - hashes - 1].to_string();
+    }
     let mut res = String::new();
-    res.push_str("TraitFrom");
-    for c in rooted_path.to_string_lossy().chars() {
-        if c.is_alphanumeric() {
+    let mut chars = lit[1..lit.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
             res.push(c);
-        } else {
-            res.push_str(&format!("{:x}", c as u32));
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some('t') => res.push('\t'),
+            Some('0') => res.push('\0'),
+            Some('x') => {
+                let code = chars.by_ref().take(2).collect::<String>();
+                res.push(u8::from_str_radix(&code, 16).unwrap() as char);
+            },
+            Some('u') => {
+                let code = chars.by_ref().skip(1).take_while(|c| *c != '}')
+                    .collect::<String>();
+                res.extend(u32::from_str_radix(&code, 16).ok().and_then(char::from_u32));
+            },
+            Some(c) => res.push(c),
+            None => {},
         }
     }
     res
 }
 
-fn get_parent_type(ast: &syn::DeriveInput) -> Option<&syn::Ty> {
+// Returns whether an expression is a range, possibly in parentheses. Ranges
+// are iterators themselves, so loops don't borrow them.
+fn is_range(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Range(..) => true,
+        Expr::Group(ref inner) => is_range(inner),
+        _ => false,
+    }
+}
+
+fn get_parent_type<'a>(ast: &'a syn::DeriveInput, field: &str) -> Option<&'a syn::Ty> {
     match ast.body {
         syn::Body::Struct(ref data) => {
             data.fields().iter().filter_map(|f| {
                 f.ident.as_ref().and_then(|name| {
-                    if name.as_ref() == "_parent" {
+                    if name.as_ref() == field {
                         Some(&f.ty)
                     } else {
                         None
@@ -102,11 +357,33 @@ struct Generator<'a> {
     locals: SetChain<'a, &'a str>,
     next_ws: Option<&'a str>,
     skip_ws: bool,
+    file: FileInfo<'a>,
+    // One entry per enclosing `for` loop, set if its length is needed
+    loops: Vec<bool>,
+    whitespace: Whitespace,
+    // Template files the generated code depends on, other than the main one
+    dependencies: Vec<PathBuf>,
+    // The block being rendered, with the index of its definition
+    super_block: Option<(&'a str, usize)>,
+    // The index in `State::scopes` of the macros visible from this file
+    macro_scope: usize,
+    // One entry per enclosing macro, with the body of the `callblock` tag that
+    // invoked it, if any
+    callers: Vec<Option<Caller<'a>>>,
+    // The macros being expanded, which can't be called again from within
+    expanding: Vec<&'a Macro<'a>>,
+    // Set if the innermost enclosing `for` loop is recursive
+    recursive_loop: bool,
+    // Set inside the body of a `for` loop, where the `loop` variable works
+    in_loop: bool,
+    // Set where `break` and `continue` leave the innermost `for` loop
+    can_break: bool,
 }
 
 impl<'a> Generator<'a> {
 
-    fn new<'n>(locals: SetChain<'n, &'n str>, indent: u8) -> Generator<'n> {
+    fn new<'n>(locals: SetChain<'n, &'n str>, indent: u8, file: FileInfo<'n>,
+               whitespace: Whitespace) -> Generator<'n> {
         Generator {
             buf: String::new(),
             indent: indent,
@@ -114,35 +391,68 @@ impl<'a> Generator<'a> {
             locals: locals,
             next_ws: None,
             skip_ws: false,
+            file: file,
+            loops: Vec::new(),
+            whitespace: whitespace,
+            dependencies: Vec::new(),
+            super_block: None,
+            macro_scope: 0,
+            callers: Vec::new(),
+            expanding: Vec::new(),
+            recursive_loop: false,
+            in_loop: false,
+            can_break: false,
         }
     }
 
-    fn default<'n>() -> Generator<'n> {
-        Self::new(SetChain::new(), 0)
+    fn child<'n>(&'n mut self, file: FileInfo<'n>) -> Generator<'n> {
+        let locals = SetChain::with_parent(&self.locals);
+        let mut gen = Self::new(locals, self.indent, file, self.whitespace);
+        gen.loops.push(false);
+        gen.super_block = self.super_block;
+        gen.macro_scope = self.macro_scope;
+        gen.callers = self.callers.clone();
+        gen.expanding = self.expanding.clone();
+        gen.recursive_loop = self.recursive_loop;
+        gen.in_loop = self.in_loop;
+        gen.can_break = self.can_break;
+        gen
     }
 
-    fn child(&mut self) -> Generator {
-        let locals = SetChain::with_parent(&self.locals);
-        Self::new(locals, self.indent)
+    // Takes the results of a child generator, once it's done.
+    fn finish(self) -> (String, bool, Vec<PathBuf>) {
+        (self.buf, self.loops[0], self.dependencies)
     }
 
-    // Takes a State and generates the relevant implementations.
-    fn build(mut self, state: &'a State) -> String {
-        if !state.blocks.is_empty() {
-            if !state.derived {
-                self.define_trait(state);
-            } else {
-                let parent_type = get_parent_type(state.input.ast)
-                    .expect("expected field '_parent' in extending template struct");
-                self.deref_to_parent(state, parent_type);
-            }
+    // Appends the code generated by a child, and passes on what it needs.
+    fn absorb(&mut self, (code, needs_length, dependencies): (String, bool, Vec<PathBuf>)) {
+        self.buf.push_str(&code);
+        self.dependencies.extend(dependencies);
+        if needs_length {
+            self.use_loop_length();
+        }
+    }
 
-            let trait_nodes = if !state.derived { Some(&state.nodes[..]) } else { None };
-            self.impl_trait(state, trait_nodes);
-            self.impl_template_for_trait(state);
-        } else {
-            self.impl_template(state);
+    // Takes a State and generates the relevant implementations.
+    fn build(mut self, state: &'a State) -> Result<String, CompileError> {
+        // Without a parent field, the fields used by the templates up the
+        // inheritance chain must be defined on the struct itself.
+        let ident = state.input.ast.ident.as_ref();
+        match (state.derived(), state.input.meta.parent) {
+            (false, Some(_)) => return Err(CompileError::new(format!(
+                "'parent' attribute used on '{}', but its template doesn't extend another",
+                ident))),
+            (true, Some(field)) => match get_parent_type(state.input.ast, field) {
+                Some(ty) => self.deref_to_parent(state, field, ty),
+                None => return Err(CompileError::new(format!(
+                    "parent field '{}' not found in struct '{}'", field, ident))),
+            },
+            (true, None) => if let Some(ty) = get_parent_type(state.input.ast, "_parent") {
+                self.deref_to_parent(state, "_parent", ty);
+            },
+            (false, None) => {},
         }
+        self.impl_template(state)?;
         self.impl_display(state);
         if cfg!(feature = "iron") {
             self.impl_modifier_response(state);
@@ -150,19 +460,45 @@ impl<'a> Generator<'a> {
         if cfg!(feature = "rocket") {
             self.impl_responder(state);
         }
-        self.buf
+        Ok(self.buf)
     }
 
     // Implement `Template` for the given context struct.
-    fn impl_template(&mut self, state: &'a State) {
+    fn impl_template(&mut self, state: &'a State) -> Result<(), CompileError> {
         self.write_header(state, "::askama::Template", &[]);
         self.writeln("fn render_into(&self, writer: &mut ::std::fmt::Write) -> \
                       ::askama::Result<()> {");
-        self.handle(state, state.nodes, AstLevel::Top);
+        let root = state.contexts.last().unwrap();
+        self.handle(state, root.nodes, AstLevel::Top)?;
         self.flush_ws(&WS(false, false));
+        self.write_dependencies(state);
         self.writeln("Ok(())");
         self.writeln("}");
         self.writeln("}");
+        Ok(())
+    }
+
+    // Make the compiler track the template files used, so that the crate is
+    // rebuilt when any of them (or the configuration) changes.
+    fn write_dependencies(&mut self, state: &'a State) {
+        let mut paths = Vec::new();
+        if let Some(path) = state.input.file_info().path {
+            paths.push(path.to_path_buf());
+        }
+        for ctx in &state.contexts[1..] {
+            paths.extend(ctx.file.path.map(|path| path.to_path_buf()));
+        }
+        for file in &state.imports {
+            paths.extend(file.path.map(|path| path.to_path_buf()));
+        }
+        paths.extend(self.dependencies.drain(..));
+        paths.extend(state.input.config.file.clone());
+        let mut seen = HashSet::new();
+        for path in paths {
+            if seen.insert(path.clone()) {
+                self.writeln(&format!("include_bytes!({:?});", path.to_string_lossy()));
+            }
+        }
     }
 
     // Implement `Display` for the given context struct.
@@ -175,64 +511,14 @@ impl<'a> Generator<'a> {
     }
 
     // Implement `Deref<Parent>` for an inheriting context struct.
-    fn deref_to_parent(&mut self, state: &'a State, parent_type: &syn::Ty) {
+    fn deref_to_parent(&mut self, state: &'a State, field: &str, parent_type: &syn::Ty) {
         self.write_header(state, "::std::ops::Deref", &[]);
         let mut tokens = Tokens::new();
         parent_type.to_tokens(&mut tokens);
         self.writeln(&format!("type Target = {};", tokens.as_str()));
         self.writeln("fn deref(&self) -> &Self::Target {");
-        self.writeln("&self._parent");
-        self.writeln("}");
-        self.writeln("}");
-    }
-
-    // Implement `TraitFromPathName` for the given context struct.
-    fn impl_trait(&mut self, state: &'a State, nodes: Option<&'a [Node]>) {
-        self.write_header(state, &state.trait_name, &[]);
-        self.write_block_defs(state);
-
-        self.writeln("#[allow(unused_variables)]");
-        self.writeln(&format!(
-            "fn render_trait_into(&self, timpl: &{}, writer: &mut ::std::fmt::Write) \
-             -> ::askama::Result<()> {{",
-            state.trait_name));
-
-        if let Some(nodes) = nodes {
-            self.handle(state, nodes, AstLevel::Top);
-            self.flush_ws(&WS(false, false));
-        } else {
-            self.writeln("self._parent.render_trait_into(self, writer)?;");
-        }
-
-        self.writeln("Ok(())");
+        self.writeln(&format!("&self.{}", field));
         self.writeln("}");
-        self.flush_ws(&WS(false, false));
-        self.writeln("}");
-    }
-
-    // Implement `Template` for templates that implement a template trait.
-    fn impl_template_for_trait(&mut self, state: &'a State) {
-        self.write_header(state, "::askama::Template", &[]);
-        self.writeln("fn render_into(&self, writer: &mut ::std::fmt::Write) \
-                      -> ::askama::Result<()> {");
-        if state.derived {
-            self.writeln("self._parent.render_trait_into(self, writer)?;");
-        } else {
-            self.writeln("self.render_trait_into(self, writer)?;");
-        }
-        self.writeln("Ok(())");
-        self.writeln("}");
-        self.writeln("}");
-    }
-
-    // Defines the `TraitFromPathName` trait.
-    fn define_trait(&mut self, state: &'a State) {
-        self.writeln(&format!("trait {} {{", state.trait_name));
-        self.write_block_defs(state);
-        self.writeln(&format!(
-            "fn render_trait_into(&self, timpl: &{}, writer: &mut ::std::fmt::Write) \
-             -> ::askama::Result<()>;",
-            state.trait_name));
         self.writeln("}");
     }
 
@@ -326,64 +612,97 @@ impl<'a> Generator<'a> {
 
     /* Helper methods for handling node types */
 
-    fn handle(&mut self, state: &'a State, nodes: &'a [Node], level: AstLevel) {
-        for n in nodes {
+    fn handle(&mut self, state: &'a State, nodes: &'a [Node], level: AstLevel)
+              -> Result<(), CompileError> {
+        for (i, n) in nodes.iter().enumerate() {
             match *n {
                 Node::Lit(lws, val, rws) => { self.write_lit(lws, val, rws); }
                 Node::Comment() => {},
-                Node::Expr(ref ws, ref val) => { self.write_expr(state, ws, val); },
+                Node::Raw(ref ws1, lws, val, rws, ref ws2) => {
+                    self.handle_ws(ws1);
+                    self.write_lit(lws, val, rws);
+                    self.handle_ws(ws2);
+                },
+                Node::Expr(ref ws, Expr::Call(ref path, ref args)) if path == &["super"] => {
+                    self.write_super(state, ws, path[0], args)?;
+                },
+                Node::Expr(ref ws, Expr::Call(ref path, ref args))
+                        if path.len() == 2 && self.imports(state).contains_key(path[0]) => {
+                    self.write_call(state, ws, Some(path[0]), path[1], args, None)?;
+                },
+                Node::Expr(ref ws, Expr::Call(ref path, ref args)) if path == &["caller"] => {
+                    self.write_caller(state, ws, path[0], args)?;
+                },
+                Node::Expr(ref ws, Expr::Call(ref path, ref args)) if path == &["loop"] => {
+                    self.write_recursion(ws, path[0], args)?;
+                },
+                Node::Expr(ref ws, ref val) => { self.write_expr(state, ws, val)?; },
                 Node::LetDecl(ref ws, ref var) => { self.write_let_decl(ws, var); },
-                Node::Let(ref ws, ref var, ref val) => { self.write_let(ws, var, val); },
+                Node::Let(ref ws, ref var, ref val) => { self.write_let(ws, var, val)?; },
                 Node::Cond(ref conds, ref ws) => {
-                    self.write_cond(state, conds, ws);
+                    self.write_cond(state, conds, ws, level.inner())?;
+                },
+                Node::Loop(ref l) => { self.write_loop(state, l, level.inner())?; },
+                Node::Break(ref ws, keyword) | Node::Continue(ref ws, keyword) => {
+                    self.write_loop_control(ws, keyword)?;
+                    // Nothing after a loop control tag can be rendered, so
+                    // only whitespace and comments may follow it.
+                    let unreachable = nodes[i + 1..].iter().any(|n| match *n {
+                        Node::Lit(_, val, _) => !val.is_empty(),
+                        Node::Comment() => false,
+                        _ => true,
+                    });
+                    if unreachable {
+                        return Err(self.err_at(keyword, format!(
+                            "'{{% {} %}}' must be the last tag in its block, \
+                             because nothing after it is rendered", keyword)));
+                    }
+                    break;
                 },
-                Node::Loop(ref ws1, ref var, ref iter, ref body, ref ws2) => {
-                    self.write_loop(state, ws1, var, iter, body, ws2);
+                Node::Match(ref ws1, ref expr, ref arms, ref ws2) => {
+                    self.write_match(state, ws1, expr, arms, ws2, level.inner())?;
                 },
                 Node::BlockDef(ref ws1, name, _, ref ws2) => {
                     if let AstLevel::Nested = level {
-                        panic!("blocks ('{}') are only allowed at the top level", name);
+                        return Err(self.err_at(name, format!(
+                            "blocks ('{}') are not allowed in included templates or macros",
+                            name)));
                     }
-                    self.write_block(ws1, name, ws2);
+                    self.write_block(state, ws1, name, ws2)?;
                 },
-                Node::Include(ref ws, path) => {
-                    self.handle_include(state, ws, path);
+                Node::Include(ref ws, path, ignore_missing, ref context) => {
+                    self.handle_include(state, ws, path, ignore_missing, context.as_ref())?;
                 },
-                Node::Call(ref ws, name, ref args) => self.write_call(state, ws, name, args),
-                Node::Macro(_, _) |
-                Node::Extends(_) => {
-                    if let AstLevel::Nested = level {
-                        panic!("macro or extend blocks only allowed at the top level");
+                Node::Call(ref ws, scope, name, ref args, ref caller) => {
+                    self.write_call(state, ws, scope, name, args, caller.as_ref())?;
+                },
+                Node::Import(ref ws, _, scope) => {
+                    if level != AstLevel::Top {
+                        return Err(self.err_at(scope,
+                            "import blocks only allowed at the top level"));
+                    }
+                    self.handle_ws(ws);
+                },
+                Node::Macro(name, _) => {
+                    if level != AstLevel::Top {
+                        return Err(self.err_at(name,
+                            "macro blocks only allowed at the top level"));
+                    }
+                },
+                Node::Extends(ref path) => {
+                    if level != AstLevel::Top {
+                        let at = match *path { Expr::StrLit(s) => s, _ => "" };
+                        return Err(self.err_at(at,
+                            "extend blocks only allowed at the top level"));
                     }
                 },
             }
         }
+        Ok(())
     }
 
-    fn write_block_defs(&mut self, state: &'a State) {
-        for b in &state.blocks {
-            if let Node::BlockDef(ref ws1, name, ref nodes, ref ws2) = **b {
-                self.writeln("#[allow(unused_variables)]");
-                self.writeln(&format!(
-                    "fn render_block_{}_into(&self, writer: &mut ::std::fmt::Write) \
-                     -> ::askama::Result<()> {{",
-                    name));
-                self.prepare_ws(ws1);
-
-                self.locals.push();
-                self.handle(state, nodes, AstLevel::Nested);
-                self.locals.pop();
-
-                self.flush_ws(ws2);
-                self.writeln("Ok(())");
-                self.writeln("}");
-            } else {
-                panic!("only block definitions allowed here");
-            }
-        }
-    }
-
-    fn write_cond(&mut self, state: &'a State, conds: &'a [Cond], ws: &WS) {
+    fn write_cond(&mut self, state: &'a State, conds: &'a [Cond], ws: &WS, level: AstLevel)
+                  -> Result<(), CompileError> {
         for (i, &(ref cws, ref cond, ref nodes)) in conds.iter().enumerate() {
             self.handle_ws(cws);
             match *cond {
@@ -394,7 +713,7 @@ impl<'a> Generator<'a> {
                         self.dedent();
                         self.write("} else if ");
                     }
-                    self.visit_expr(expr);
+                    self.visit_expr(expr)?;
                 },
                 None => {
                     self.dedent();
@@ -403,79 +722,455 @@ impl<'a> Generator<'a> {
             }
             self.writeln(" {");
             self.locals.push();
-            self.handle(state, nodes, AstLevel::Nested);
+            self.handle(state, nodes, level)?;
             self.locals.pop();
         }
         self.handle_ws(ws);
         self.writeln("}");
+        Ok(())
     }
 
-    fn write_loop(&mut self, state: &'a State, ws1: &WS, var: &'a Target, iter: &Expr,
-                  body: &'a [Node], ws2: &WS) {
-        self.handle_ws(ws1);
+    fn write_loop(&mut self, state: &'a State, l: &'a Loop, level: AstLevel)
+                  -> Result<(), CompileError> {
+        self.handle_ws(&l.ws1);
         self.locals.push();
-        self.write("for (_loop_index, ");
-        let targets = self.visit_target(var);
-        for name in &targets {
-            self.locals.insert(name);
-            self.write(name);
+        if l.recursive {
+            // Each level of the loop is rendered by the closure, which gets
+            // the iterable for that level and renders nested levels through
+            // `_loop_self`.
+            self.write(if is_range(&l.iter) {
+                "::askama::helpers::recursive_loop(("
+            } else {
+                "::askama::helpers::recursive_loop(&("
+            });
+            self.visit_expr(&l.iter)?;
+            self.writeln("), writer, |_loop_self, _iter, writer| {");
+            self.write("let _iter = _iter.into_iter()");
+        } else {
+            self.writeln("{");
+            if is_range(&l.iter) {
+                self.write("let _iter = (");
+            } else {
+                self.write("let _iter = (&");
+            }
+            self.visit_expr(&l.iter)?;
+            self.write(").into_iter()");
+        }
+        if let Some(ref cond) = l.cond {
+            // The closure gets a reference to each item, which the pattern
+            // takes apart, so the condition sees the same names as the body.
+            self.locals.push();
+            self.write(".filter(|&");
+            self.visit_target(&l.var);
+            self.write("| ");
+            self.visit_expr(cond)?;
+            self.write(")");
+            self.locals.pop();
+        }
+        self.writeln(";");
+        let (length_pos, length_indent) = (self.buf.len(), self.indent);
+        let has_else = !l.else_block.is_empty();
+        if has_else {
+            self.writeln("let mut _did_loop = false;");
+        }
+        self.write("for (");
+        self.visit_target(&l.var);
+        self.writeln(", _loop_item) in ::askama::helpers::TemplateLoop::new(_iter) {");
+        if has_else {
+            self.writeln("_did_loop = true;");
         }
-        self.write(") in (&");
-        self.visit_expr(iter);
-        self.writeln(").into_iter().enumerate() {");
 
-        self.handle(state, body, AstLevel::Nested);
-        self.handle_ws(ws2);
+        self.loops.push(false);
+        let (recursive_loop, in_loop, can_break) =
+            (self.recursive_loop, self.in_loop, self.can_break);
+        self.recursive_loop = l.recursive;
+        self.in_loop = true;
+        self.can_break = true;
+        self.handle(state, &l.body, level)?;
+        self.recursive_loop = recursive_loop;
+        self.in_loop = in_loop;
+        self.can_break = can_break;
+        self.handle_ws(&l.ws2);
         self.writeln("}");
+        if self.loops.pop().unwrap() {
+            // Only require `ExactSizeIterator` if the body needs the length.
+            // A filtered iterator has to be collected to know its length.
+            let indent = " ".repeat(length_indent as usize * 4);
+            let mut lines = String::new();
+            if l.cond.is_some() {
+                lines.push_str(&format!("{}let _iter = _iter.collect::<Vec<_>>().into_iter();\n",
+                                        indent));
+            }
+            lines.push_str(&format!("{}let _loop_length = ::std::iter::ExactSizeIterator::len(&_iter);\n",
+                                    indent));
+            self.buf.insert_str(length_pos, &lines);
+        }
         self.locals.pop();
+
+        if has_else {
+            self.writeln("if !_did_loop {");
+            self.locals.push();
+            self.handle(state, &l.else_block, level)?;
+            self.locals.pop();
+            self.handle_ws(&l.ws3);
+            self.writeln("}");
+        }
+        if l.recursive {
+            self.writeln("Ok(())");
+            self.dedent();
+            self.writeln("})?;");
+        } else {
+            self.writeln("}");
+        }
+        Ok(())
+    }
+
+    // Renders the innermost recursive loop again, for a nested iterable.
+    fn write_recursion(&mut self, ws: &WS, fragment: &str, args: &[Expr])
+                       -> Result<(), CompileError> {
+        if !self.recursive_loop {
+            return Err(self.err_at(fragment,
+                "loop() is only allowed inside a recursive for loop"));
+        }
+        if args.len() != 1 {
+            return Err(self.err_at(fragment, format!(
+                "loop() takes exactly 1 argument, {} given", args.len())));
+        }
+        self.handle_ws(ws);
+        self.write(if is_range(&args[0]) {
+            "_loop_self.recurse(("
+        } else {
+            "_loop_self.recurse(&("
+        });
+        self.visit_expr(&args[0])?;
+        self.writeln("), writer)?;");
+        Ok(())
     }
 
-    fn write_call(&mut self, state: &'a State, ws: &WS, name: &str, args: &[Expr]) {
-        let def = state.macros.get(name).expect(&format!("macro '{}' not found", name));
+    fn write_loop_control(&mut self, ws: &WS, keyword: &str) -> Result<(), CompileError> {
+        if !self.can_break {
+            return Err(self.err_at(keyword, if self.in_loop {
+                format!("'{{% {} %}}' can't leave a for loop around a callblock tag \
+                         from its body", keyword)
+            } else {
+                format!("'{{% {} %}}' is only allowed inside a for loop", keyword)
+            }));
+        }
         self.handle_ws(ws);
+        self.writeln(&format!("{};", keyword));
+        Ok(())
+    }
+
+    fn write_match(&mut self, state: &'a State, ws1: &WS, expr: &Expr, arms: &'a [When],
+                   ws2: &WS, level: AstLevel) -> Result<(), CompileError> {
+        self.handle_ws(ws1);
+        self.write("match &(");
+        self.visit_expr(expr)?;
+        self.writeln(") {");
+        for (i, &(ref ws, ref target, ref body)) in arms.iter().enumerate() {
+            self.handle_ws(ws);
+            if i > 0 {
+                self.writeln("}");
+            }
+            self.locals.push();
+            // The matched expression is borrowed, and string literal patterns
+            // are not dereferenced automatically like other patterns are.
+            if let Target::StrLit(_) = *target {
+                self.write("&");
+            }
+            self.visit_target(target);
+            self.writeln(" => {");
+            self.handle(state, body, level)?;
+            self.locals.pop();
+        }
+        self.handle_ws(ws2);
+        self.writeln("}");
+        self.writeln("}");
+        Ok(())
+    }
+
+    fn imports(&self, state: &'a State) -> &'a HashMap<&'a str, usize> {
+        &state.scopes[self.macro_scope].imports
+    }
+
+    fn write_call(&mut self, state: &'a State, ws: &WS, scope: Option<&'a str>, name: &str,
+                  args: &'a [Expr], caller: Option<&'a Macro<'a>>)
+                  -> Result<(), CompileError> {
+        let (idx, full_name) = match scope {
+            Some(scope) => match self.imports(state).get(scope) {
+                Some(&idx) => (idx, format!("{}::{}", scope, name)),
+                None => return Err(self.err_at(scope, format!(
+                    "no macros imported as '{}'", scope))),
+            },
+            None => (self.macro_scope, name.to_string()),
+        };
+        let (file, def) = match state.scopes[idx].macros.get(name) {
+            Some(&(file, def)) => (file, def),
+            None => return Err(self.err_at(name, format!("macro '{}' not found", full_name))),
+        };
+        if self.expanding.iter().any(|m| ptr::eq(*m, def)) {
+            return Err(self.err_at(name, format!(
+                "macro '{}' calls itself, which is not supported; \
+                 use a recursive for loop to render nested data", full_name)));
+        }
+        let values = self.match_args(name, &full_name, def, args)?;
+        // Within the macro, `caller()` renders the body of this call, if any
+        let caller = caller.map(|def| Caller {
+            body: MacroRef { file: self.file, def, scope: self.macro_scope },
+            expanding: self.expanding.clone(),
+            in_loop: self.in_loop,
+            recursive_loop: self.recursive_loop,
+            depth: self.callers.len(),
+            needs_length: Rc::new(Cell::new(false)),
+        });
+        let captured = match caller {
+            Some(ref caller) if caller.in_loop => Some(caller.clone()),
+            _ => None,
+        };
+        let mut callers = self.callers.clone();
+        callers.push(caller);
+        let expanding = self.expanding.clone();
+        let length_pos = match captured {
+            Some(ref caller) => {
+                self.writeln("{");
+                self.writeln(&format!("let _caller_loop_item{} = _loop_item;", caller.depth));
+                if caller.recursive_loop {
+                    self.writeln(&format!("let _caller_loop_self{} = _loop_self;",
+                                          caller.depth));
+                }
+                (self.buf.len(), self.indent)
+            },
+            None => (0, 0),
+        };
+        self.expand_macro(state, ws, MacroRef { file, def, scope: idx }, &values, callers,
+                          expanding, None)?;
+        if let Some(caller) = captured {
+            if caller.needs_length.get() {
+                let indent = " ".repeat(length_pos.1 as usize * 4);
+                self.buf.insert_str(length_pos.0, &format!(
+                    "{}let _caller_loop_length{} = _loop_length;\n", indent, caller.depth));
+                self.use_loop_length();
+            }
+            self.writeln("}");
+        }
+        Ok(())
+    }
+
+    // Renders the body of the `callblock` tag that invoked the current macro.
+    fn write_caller(&mut self, state: &'a State, ws: &WS, fragment: &str, args: &'a [Expr])
+                    -> Result<(), CompileError> {
+        let mut callers = self.callers.clone();
+        let caller = match callers.pop() {
+            Some(Some(caller)) => caller,
+            _ => return Err(self.err_at(fragment,
+                "caller() is only allowed in macros called with a callblock tag")),
+        };
+        // The body is expanded as if it was where the `callblock` tag is
+        let values = self.match_args(fragment, "caller", caller.body.def, args)?;
+        self.expand_macro(state, ws, caller.body, &values, callers, caller.expanding.clone(),
+                          Some(&caller))
+    }
+
+    // Renders a macro, or the body of a `callblock` tag if `caller` is set.
+    fn expand_macro(&mut self, state: &'a State, ws: &WS, mac: MacroRef<'a>,
+                    values: &[Option<&Expr>], callers: Vec<Option<Caller<'a>>>,
+                    expanding: Vec<&'a Macro<'a>>, caller: Option<&Caller<'a>>)
+                    -> Result<(), CompileError> {
+        self.flush_ws(ws);
         self.locals.push();
         self.writeln("{");
-        self.prepare_ws(&def.ws1);
-        for (i, arg) in def.args.iter().enumerate() {
-            self.write(&format!("let {} = &", arg));
-            self.locals.insert(arg);
-            self.visit_expr(args.get(i)
-                .expect(&format!("macro '{}' takes more than {} arguments", name, i)));
-            self.writeln(";");
-        }
-        self.handle(state, &def.nodes, AstLevel::Nested);
-        self.flush_ws(&def.ws2);
+        let given = mac.def.args.iter().zip(values)
+            .filter_map(|(&(param, _), value)| value.map(|value| (param, value)))
+            .collect::<Vec<_>>();
+        self.write_bindings(&given)?;
+        // Default values belong to the macro definition, so they only see
+        // the parameters before them and the template's fields.
+        let parts = {
+            let mut gen = self.child(mac.file);
+            gen.locals = SetChain::new();
+            for (&(param, ref default), value) in mac.def.args.iter().zip(values) {
+                if let (None, &Some(ref default)) = (*value, default) {
+                    gen.write(&format!("let {} = &", param));
+                    gen.visit_expr(default)?;
+                    gen.writeln(";");
+                }
+                gen.locals.insert(param);
+            }
+            gen.finish()
+        };
+        self.absorb(parts);
+        for &(param, _) in &mac.def.args {
+            self.locals.insert(param);
+        }
+        let parts = {
+            // The macro body is generated in the context of the file defining it
+            let mut gen = self.child(mac.file);
+            gen.macro_scope = mac.scope;
+            gen.callers = callers;
+            gen.expanding = expanding;
+            gen.expanding.push(mac.def);
+            let mut length_pos = None;
+            if let Some(caller) = caller {
+                // The body of a `callblock` tag sees the loop around the tag
+                gen.in_loop = caller.in_loop;
+                gen.recursive_loop = caller.recursive_loop;
+                gen.can_break = false;
+                if caller.in_loop {
+                    gen.writeln(&format!("let _loop_item = _caller_loop_item{};", caller.depth));
+                    if caller.recursive_loop {
+                        gen.writeln(&format!("let _loop_self = _caller_loop_self{};",
+                                             caller.depth));
+                    }
+                    length_pos = Some((gen.buf.len(), gen.indent));
+                }
+            }
+            gen.prepare_ws(&mac.def.ws1);
+            gen.handle(state, &mac.def.nodes, AstLevel::Nested)?;
+            gen.flush_ws(&mac.def.ws2);
+            if let (Some(caller), Some((pos, indent))) = (caller, length_pos) {
+                if gen.loops[0] {
+                    let indent = " ".repeat(indent as usize * 4);
+                    gen.buf.insert_str(pos, &format!(
+                        "{}let _loop_length = _caller_loop_length{};\n", indent, caller.depth));
+                    caller.needs_length.set(true);
+                    gen.loops[0] = false;
+                }
+            }
+            gen.finish()
+        };
+        self.absorb(parts);
         self.writeln("}");
         self.locals.pop();
+        self.prepare_ws(ws);
+        Ok(())
     }
 
-    fn handle_include(&mut self, state: &'a State, ws: &WS, path: &str) {
-        self.prepare_ws(ws);
-        let path = path::find_template_from_path(path, Some(&state.input.path));
-        let src = path::get_template_source(&path);
-        let nodes = parser::parse(&src);
-        let nested = {
-            let mut gen = self.child();
-            gen.handle(state, &nodes, AstLevel::Nested);
-            gen.buf
-        };
-        self.buf.push_str(&nested);
+    // Binds each name to a reference to its value. The values are bound all
+    // at once, so that they are all evaluated before any of the names refer
+    // to them.
+    fn write_bindings(&mut self, bindings: &[(&str, &Expr)]) -> Result<(), CompileError> {
+        if bindings.is_empty() {
+            return Ok(());
+        }
+        self.write("let (");
+        for &(name, _) in bindings {
+            self.write(&format!("{}, ", name));
+        }
+        self.write(") = (");
+        for &(_, value) in bindings {
+            self.write("&");
+            self.visit_expr(value)?;
+            self.write(", ");
+        }
+        self.writeln(");");
+        Ok(())
+    }
+
+    // Finds the value for each of the macro's parameters: the positional
+    // arguments, followed by the named ones, or else the parameter's default.
+    // Returns the argument given for each parameter of the macro, or `None`
+    // for parameters left to their default value.
+    fn match_args<'e>(&self, name: &str, full_name: &str, def: &'e Macro<'e>,
+                      args: &'e [Expr<'e>]) -> Result<Vec<Option<&'e Expr<'e>>>, CompileError> {
+        let mut values = vec![None; def.args.len()];
+        let mut named = false;
+        for (i, arg) in args.iter().enumerate() {
+            if let Expr::NamedArg(arg_name, ref value) = *arg {
+                named = true;
+                let pos = match def.args.iter().position(|&(param, _)| param == arg_name) {
+                    Some(pos) => pos,
+                    None => return Err(self.err_at(arg_name, format!(
+                        "macro '{}' has no parameter named '{}'", full_name, arg_name))),
+                };
+                if values[pos].is_some() {
+                    return Err(self.err_at(arg_name, format!(
+                        "parameter '{}' of macro '{}' is given more than once",
+                        arg_name, full_name)));
+                }
+                values[pos] = Some(&**value);
+            } else if named {
+                return Err(self.err_at(name, format!(
+                    "positional arguments to macro '{}' must come before named ones",
+                    full_name)));
+            } else if i >= def.args.len() {
+                return Err(self.err_at(name, format!(
+                    "macro '{}' takes {} arguments, but {} were given",
+                    full_name, def.args.len(), args.len())));
+            } else {
+                values[i] = Some(arg);
+            }
+        }
+        for (&(param, ref default), value) in def.args.iter().zip(&values) {
+            if value.is_none() && default.is_none() {
+                return Err(self.err_at(name, format!(
+                    "missing argument for parameter '{}' of macro '{}'", param, full_name)));
+            }
+        }
+        Ok(values)
+    }
+
+    fn handle_include(&mut self, state: &'a State, ws: &WS, path: &str, ignore_missing: bool,
+                      context: Option<&'a Vec<(&'a str, Expr<'a>)>>)
+                      -> Result<(), CompileError> {
         self.flush_ws(ws);
+        let path = match path::find_template_from_path(&str_lit_value(path), self.file.path,
+                                                       &state.input.dirs) {
+            Ok(path) => path,
+            Err(_) if ignore_missing => {
+                self.prepare_ws(ws);
+                return Ok(());
+            },
+            Err(err) => return Err(err),
+        };
+        let src = path::get_template_source(&path)?;
+        if let Some(context) = context {
+            for &(name, _) in context {
+                if context.iter().filter(|&&(other, _)| other == name).count() > 1 {
+                    return Err(self.err_at(name, format!(
+                        "'{}' is given more than once in the include context", name)));
+                }
+            }
+            self.writeln("{");
+            let bindings = context.iter().map(|&(name, ref value)| (name, value))
+                .collect::<Vec<_>>();
+            self.write_bindings(&bindings)?;
+        }
+        let parts = {
+            let file = FileInfo { path: Some(&path), source: &src };
+            let nodes = parser::parse(&file, state.input.syntax)?;
+            let mut gen = self.child(file);
+            if let Some(context) = context {
+                // Only the names from the context are local to the included
+                // template; any other name refers to a field of the template.
+                // The enclosing loop isn't visible either.
+                gen.locals = SetChain::new();
+                gen.in_loop = false;
+                gen.can_break = false;
+                gen.recursive_loop = false;
+                for &(name, _) in context {
+                    gen.locals.insert(name);
+                }
+            }
+            gen.handle(state, &nodes, AstLevel::Nested)?;
+            gen.finish()
+        };
+        self.absorb(parts);
+        if context.is_some() {
+            self.writeln("}");
+        }
+        self.dependencies.push(path);
+        self.prepare_ws(ws);
+        Ok(())
     }
 
     fn write_let_decl(&mut self, ws: &WS, var: &'a Target) {
         self.handle_ws(ws);
         self.write("let ");
-        match *var {
-            Target::Name(name) => {
-                self.locals.insert(name);
-                self.write(name);
-            },
-        }
+        self.visit_target(var);
         self.writeln(";");
     }
 
-    fn write_let(&mut self, ws: &WS, var: &'a Target, val: &Expr) {
+    fn write_let(&mut self, ws: &WS, var: &'a Target, val: &Expr) -> Result<(), CompileError> {
         self.handle_ws(ws);
         match *var {
             Target::Name(name) => {
@@ -484,24 +1179,76 @@ impl<'a> Generator<'a> {
                     self.locals.insert(name);
                 }
                 self.write(name);
+                self.write(" = ");
+                self.visit_expr(val)?;
+            },
+            _ => {
+                // Destructure a borrow, so that fields are not moved out of
+                // the context and the bindings become references.
+                self.write("let ");
+                self.visit_target(var);
+                self.write(" = &(");
+                self.visit_expr(val)?;
+                self.write(")");
             },
         }
-        self.write(" = ");
-        self.visit_expr(val);
         self.writeln(";");
+        Ok(())
     }
 
-    fn write_block(&mut self, ws1: &WS, name: &str, ws2: &WS) {
-        self.flush_ws(ws1);
-        self.writeln(&format!("timpl.render_block_{}_into(writer)?;", name));
-        self.prepare_ws(ws2);
+    fn write_block(&mut self, state: &'a State, ws1: &WS, name: &'a str, ws2: &WS)
+                   -> Result<(), CompileError> {
+        self.write_block_def(state, name, 0, &WS(ws1.0, ws2.1))
     }
 
-    fn write_expr(&mut self, state: &'a State, ws: &WS, s: &Expr) {
+    // Renders the definition of the current block from the next template up
+    // the inheritance chain.
+    fn write_super(&mut self, state: &'a State, ws: &WS, fragment: &str, args: &[Expr])
+                   -> Result<(), CompileError> {
+        if !args.is_empty() {
+            return Err(self.err_at(fragment, "super() takes no arguments"));
+        }
+        let (name, level) = match self.super_block {
+            Some(cur) => cur,
+            None => return Err(self.err_at(fragment, "super() is only allowed inside a block")),
+        };
+        if level + 1 >= state.blocks[name].len() {
+            return Err(self.err_at(fragment, format!(
+                "block '{}' has no parent definition to render with super()", name)));
+        }
+        self.write_block_def(state, name, level + 1, ws)
+    }
+
+    // Renders the given definition of a block, counting from the most
+    // derived template, in the context of the file that defines it.
+    fn write_block_def(&mut self, state: &'a State, name: &'a str, level: usize, outer: &WS)
+                       -> Result<(), CompileError> {
+        let (file, def) = state.blocks[name][level];
+        let (ws1, nodes, ws2) = match *def {
+            Node::BlockDef(ref ws1, _, ref nodes, ref ws2) => (ws1, nodes, ws2),
+            _ => panic!("only block definitions allowed here"),
+        };
+        self.flush_ws(outer);
+        self.writeln("{");
+        let parts = {
+            let mut gen = self.child(file);
+            gen.super_block = Some((name, level));
+            gen.prepare_ws(ws1);
+            gen.handle(state, nodes, AstLevel::Block)?;
+            gen.flush_ws(ws2);
+            gen.finish()
+        };
+        self.absorb(parts);
+        self.writeln("}");
+        self.prepare_ws(outer);
+        Ok(())
+    }
+
+    fn write_expr(&mut self, state: &'a State, ws: &WS, s: &Expr) -> Result<(), CompileError> {
         self.handle_ws(ws);
-        self.write("let askama_expr = &");
-        let wrapped = self.visit_expr(s);
-        self.writeln(";");
+        self.write("let askama_expr = &(");
+        let wrapped = self.visit_expr(s)?;
+        self.writeln(");");
 
         use self::DisplayWrap::*;
         use super::input::EscapeMode::*;
@@ -513,10 +1260,13 @@ impl<'a> Generator<'a> {
             (Unwrapped, &Html) => "&::askama::MarkupDisplay::from(askama_expr)",
         });
         self.writeln("))?;");
+        Ok(())
     }
 
     fn write_lit(&mut self, lws: &'a str, val: &str, rws: &'a str) {
-        assert!(self.next_ws.is_none());
+        // Whitespace left pending by a node that produces no output (like a
+        // comment or a macro definition) is kept.
+        self.flush_ws(&WS(false, false));
         if !lws.is_empty() {
             if self.skip_ws {
                 self.skip_ws = false;
@@ -538,28 +1288,39 @@ impl<'a> Generator<'a> {
 
     /* Visitor methods for expression types */
 
-    fn visit_expr(&mut self, expr: &Expr) -> DisplayWrap {
+    fn visit_expr(&mut self, expr: &Expr) -> Result<DisplayWrap, CompileError> {
         match *expr {
+            Expr::BoolLit(s) => self.visit_bool_lit(s),
             Expr::NumLit(s) => self.visit_num_lit(s),
             Expr::StrLit(s) => self.visit_str_lit(s),
+            Expr::CharLit(s) => self.visit_char_lit(s),
             Expr::Var(s) => self.visit_var(s),
+            Expr::Path(ref path) => self.visit_path(path),
             Expr::Attr(ref obj, name) => self.visit_attr(obj, name),
+            Expr::Index(ref obj, ref key) => self.visit_index(obj, key),
             Expr::Filter(name, ref args) => self.visit_filter(name, args),
+            Expr::Unary(op, ref inner) => self.visit_unary(op, inner),
             Expr::BinOp(op, ref left, ref right) =>
                 self.visit_binop(op, left, right),
+            Expr::Range(op, ref left, ref right) => self.visit_range(op, left, right),
             Expr::Group(ref inner) => self.visit_group(inner),
+            Expr::Tuple(ref items) => self.visit_tuple(items),
+            Expr::Array(ref items) => self.visit_array(items),
             Expr::MethodCall(ref obj, method, ref args) =>
                 self.visit_method_call(obj, method, args),
+            Expr::Call(ref path, ref args) => self.visit_call(path, args),
+            Expr::NamedArg(name, _) => Err(self.err_at(name,
+                "named arguments are only allowed in macro calls")),
         }
     }
 
-    fn visit_filter(&mut self, name: &str, args: &[Expr]) -> DisplayWrap {
+    fn visit_filter(&mut self, name: &str, args: &[Expr]) -> Result<DisplayWrap, CompileError> {
         if name == "format" {
-            self._visit_format_filter(args);
-            return DisplayWrap::Unwrapped;
+            self._visit_format_filter(args)?;
+            return Ok(DisplayWrap::Unwrapped);
         } else if name == "join" {
-            self._visit_join_filter(args);
-            return DisplayWrap::Unwrapped;
+            self._visit_join_filter(args)?;
+            return Ok(DisplayWrap::Unwrapped);
         }
 
         if filters::BUILT_IN_FILTERS.contains(&name) {
@@ -568,120 +1329,296 @@ impl<'a> Generator<'a> {
             self.write(&format!("filters::{}(&", name));
         }
 
-        self._visit_filter_args(args);
+        self._visit_filter_args(args)?;
         self.write(")?");
-        if name == "safe" || name == "escape" || name == "e" || name == "json" {
+        Ok(if name == "safe" || name == "escape" || name == "e" || name == "json" {
             DisplayWrap::Wrapped
         } else {
             DisplayWrap::Unwrapped
-        }
+        })
     }
 
-    fn _visit_format_filter(&mut self, args: &[Expr]) {
+    fn _visit_format_filter(&mut self, args: &[Expr]) -> Result<(), CompileError> {
         self.write("format!(");
-        self._visit_filter_args(args);
+        self._visit_filter_args(args)?;
         self.write(")");
+        Ok(())
     }
 
     // Force type coercion on first argument to `join` filter (see #39).
-    fn _visit_join_filter(&mut self, args: &[Expr]) {
+    fn _visit_join_filter(&mut self, args: &[Expr]) -> Result<(), CompileError> {
         self.write("::askama::filters::join((&");
         for (i, arg) in args.iter().enumerate() {
             if i > 0 {
                 self.write(", &");
             }
-            self.visit_expr(arg);
+            self.visit_expr(arg)?;
             if i == 0 {
                 self.write(").into_iter()");
             }
         }
         self.write(")?");
+        Ok(())
     }
 
-    fn _visit_filter_args(&mut self, args: &[Expr]) {
+    fn _visit_filter_args(&mut self, args: &[Expr]) -> Result<(), CompileError> {
         for (i, arg) in args.iter().enumerate() {
             if i > 0 {
                 self.write(", &");
             }
-            self.visit_expr(arg);
+            self.visit_expr(arg)?;
         }
+        Ok(())
     }
 
-    fn visit_attr(&mut self, obj: &Expr, attr: &str) -> DisplayWrap {
+    fn visit_attr(&mut self, obj: &Expr, attr: &str) -> Result<DisplayWrap, CompileError> {
         if let Expr::Var(name) = *obj {
             if name == "loop" {
-                self.write("_loop_index");
-                if attr == "index" {
-                    self.write(" + 1");
-                    return DisplayWrap::Unwrapped;
-                } else if attr == "index0" {
-                    return DisplayWrap::Unwrapped;
-                } else {
-                    panic!("unknown loop variable");
+                let code = match attr {
+                    "index" => "(_loop_item.index + 1)",
+                    "index0" => "_loop_item.index",
+                    "first" => "_loop_item.first",
+                    "last" => "_loop_item.last",
+                    "length" => "_loop_length",
+                    "revindex" => "(_loop_length - _loop_item.index)",
+                    "revindex0" => "(_loop_length - _loop_item.index - 1)",
+                    "depth" => "(_loop_self.depth + 1)",
+                    "depth0" => "_loop_self.depth",
+                    _ => return Err(self.err_at(attr,
+                        format!("unknown loop variable 'loop.{}'", attr))),
+                };
+                if !self.in_loop {
+                    return Err(self.err_at(attr, format!(
+                        "'loop.{}' is only available inside a for loop", attr)));
                 }
+                if code.contains("_loop_self") && !self.recursive_loop {
+                    return Err(self.err_at(attr, format!(
+                        "'loop.{}' is only available in recursive for loops", attr)));
+                }
+                if code.contains("_loop_length") {
+                    self.use_loop_length();
+                }
+                self.write(code);
+                return Ok(DisplayWrap::Unwrapped);
             }
         }
-        self.visit_expr(obj);
+        self.visit_expr(obj)?;
         self.write(&format!(".{}", attr));
-        DisplayWrap::Unwrapped
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_index(&mut self, obj: &Expr, key: &Expr) -> Result<DisplayWrap, CompileError> {
+        self.visit_expr(obj)?;
+        self.write("[");
+        self.visit_expr(key)?;
+        self.write("]");
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_method_call(&mut self, obj: &Expr, method: &str, args: &[Expr])
+                         -> Result<DisplayWrap, CompileError> {
+        if let Expr::Var("loop") = *obj {
+            return self._visit_loop_method(method, args);
+        }
+        self.visit_expr(obj)?;
+        self.write(&format!(".{}", method));
+        self._visit_args(args)?;
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_call(&mut self, path: &[&str], args: &[Expr]) -> Result<DisplayWrap, CompileError> {
+        // These render template content where they are, rather than
+        // producing a value, so they can't be part of a larger expression.
+        if path == ["super"] || path == ["caller"] || path == ["loop"] {
+            return Err(self.err_at(path[0], format!(
+                "{0}() can only be used on its own, as in '{{{{ {0}() }}}}'", path[0])));
+        }
+        self.visit_path(path)?;
+        self._visit_args(args)?;
+        Ok(DisplayWrap::Unwrapped)
     }
 
-    fn visit_method_call(&mut self, obj: &Expr, method: &str, args: &[Expr]) -> DisplayWrap {
-        self.visit_expr(obj);
-        self.write(&format!(".{}(", method));
+    fn _visit_args(&mut self, args: &[Expr]) -> Result<(), CompileError> {
+        self.write("(");
         for (i, arg) in args.iter().enumerate() {
             if i > 0 {
                 self.write(", ");
             }
-            self.visit_expr(arg);
+            self.visit_expr(arg)?;
         }
         self.write(")");
-        DisplayWrap::Unwrapped
+        Ok(())
     }
 
-    fn visit_binop(&mut self, op: &str, left: &Expr, right: &Expr) -> DisplayWrap {
-        self.visit_expr(left);
+    fn _visit_loop_method(&mut self, method: &str, args: &[Expr])
+                          -> Result<DisplayWrap, CompileError> {
+        if method != "cycle" {
+            return Err(self.err_at(method, format!("unknown loop method 'loop.{}'", method)));
+        } else if args.is_empty() {
+            return Err(self.err_at(method, "loop.cycle() needs at least one argument"));
+        } else if !self.in_loop {
+            return Err(self.err_at(method, "loop.cycle() is only available inside a for loop"));
+        }
+        self.write("[");
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.write("&");
+            self.visit_expr(arg)?;
+        }
+        self.write(&format!("][_loop_item.index % {}]", args.len()));
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_unary(&mut self, op: &str, inner: &Expr) -> Result<DisplayWrap, CompileError> {
+        self.write(op);
+        self.visit_expr(inner)?;
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_binop(&mut self, op: &str, left: &Expr, right: &Expr)
+                   -> Result<DisplayWrap, CompileError> {
+        self.visit_expr(left)?;
         self.write(&format!(" {} ", op));
-        self.visit_expr(right);
-        DisplayWrap::Unwrapped
+        self.visit_expr(right)?;
+        Ok(DisplayWrap::Unwrapped)
     }
 
-    fn visit_group(&mut self, inner: &Expr) -> DisplayWrap {
+    fn visit_range(&mut self, op: &str, left: &Option<Box<Expr>>, right: &Option<Box<Expr>>)
+                   -> Result<DisplayWrap, CompileError> {
+        if let Some(ref left) = *left {
+            self.visit_expr(left)?;
+        }
+        self.write(op);
+        if let Some(ref right) = *right {
+            self.visit_expr(right)?;
+        }
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_group(&mut self, inner: &Expr) -> Result<DisplayWrap, CompileError> {
         self.write("(");
-        self.visit_expr(inner);
+        self.visit_expr(inner)?;
         self.write(")");
-        DisplayWrap::Unwrapped
+        Ok(DisplayWrap::Unwrapped)
     }
 
-    fn visit_var(&mut self, s: &str) -> DisplayWrap {
+    fn visit_tuple(&mut self, items: &[Expr]) -> Result<DisplayWrap, CompileError> {
+        self.write("(");
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.visit_expr(item)?;
+        }
+        if items.len() == 1 {
+            self.write(",");
+        }
+        self.write(")");
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_array(&mut self, items: &[Expr]) -> Result<DisplayWrap, CompileError> {
+        self.write("[");
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.visit_expr(item)?;
+        }
+        self.write("]");
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_var(&mut self, s: &str) -> Result<DisplayWrap, CompileError> {
         if self.locals.contains(s) {
             self.write(s);
         } else {
             self.write(&format!("self.{}", s));
         }
-        DisplayWrap::Unwrapped
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    fn visit_path(&mut self, path: &[&str]) -> Result<DisplayWrap, CompileError> {
+        self.write(&path.join("::"));
+        Ok(DisplayWrap::Unwrapped)
     }
 
-    fn visit_str_lit(&mut self, s: &str) -> DisplayWrap {
-        self.write(&format!("\"{}\"", s));
-        DisplayWrap::Unwrapped
+    fn visit_str_lit(&mut self, s: &str) -> Result<DisplayWrap, CompileError> {
+        self.write(&rust_str_lit(s));
+        Ok(DisplayWrap::Unwrapped)
     }
 
-    fn visit_num_lit(&mut self, s: &str) -> DisplayWrap {
+    fn visit_char_lit(&mut self, s: &str) -> Result<DisplayWrap, CompileError> {
         self.write(s);
-        DisplayWrap::Unwrapped
+        Ok(DisplayWrap::Unwrapped)
     }
 
-    fn visit_target_single<'t>(&mut self, name: &'t str) -> Vec<&'t str> {
-        vec![name]
+    fn visit_bool_lit(&mut self, s: &str) -> Result<DisplayWrap, CompileError> {
+        self.write(s);
+        Ok(DisplayWrap::Unwrapped)
     }
 
-    fn visit_target<'t>(&mut self, target: &'t Target) -> Vec<&'t str> {
+    fn visit_num_lit(&mut self, s: &str) -> Result<DisplayWrap, CompileError> {
+        self.write(s);
+        Ok(DisplayWrap::Unwrapped)
+    }
+
+    /* Visitor methods for targets, writing the pattern and binding its names */
+
+    fn visit_target(&mut self, target: &'a Target) {
         match *target {
-            Target::Name(s) => { self.visit_target_single(s) },
+            Target::Name(name) => {
+                self.locals.insert(name);
+                self.write(name);
+            },
+            Target::Path(ref path) => self.write(&path.join("::")),
+            Target::Tuple(ref path, ref targets) => {
+                self.write(&path.join("::"));
+                self.write("(");
+                for (i, target) in targets.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    self.visit_target(target);
+                }
+                self.write(")");
+            },
+            Target::Struct(ref path, ref fields) => {
+                self.write(&path.join("::"));
+                self.write(" { ");
+                for (i, &(name, ref target)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    self.write(&format!("{}: ", name));
+                    self.visit_target(target);
+                }
+                self.write(" }");
+            },
+            Target::BoolLit(s) |
+            Target::NumLit(s) => self.write(s),
+            Target::StrLit(s) => self.write(&rust_str_lit(s)),
+            Target::CharLit(s) => self.write(s),
+            Target::Wildcard => self.write("_"),
         }
     }
 
+    // Marks the innermost loop as needing its length to be computed.
+    fn use_loop_length(&mut self) {
+        if let Some(needs_length) = self.loops.last_mut() {
+            *needs_length = true;
+        }
+    }
+
+    /* Helper methods for reporting errors */
+
+    // Builds an error pointing at `fragment` in the template being generated.
+    fn err_at<T: Into<String>>(&self, fragment: &str, msg: T) -> CompileError {
+        CompileError::at(msg, &self.file, fragment)
+    }
+
     /* Helper methods for dealing with whitespace nodes */
 
     fn handle_ws(&mut self, ws: &WS) {
@@ -690,7 +1627,7 @@ impl<'a> Generator<'a> {
     }
 
     fn flush_ws(&mut self, ws: &WS) {
-        if self.next_ws.is_some() && !ws.0 {
+        if self.next_ws.is_some() && !ws.0 && self.whitespace == Whitespace::Preserve {
             let val = self.next_ws.unwrap();
             if !val.is_empty() {
                 self.writeln(&format!("writer.write_str({:#?})?;",
@@ -701,7 +1638,7 @@ impl<'a> Generator<'a> {
     }
 
     fn prepare_ws(&mut self, ws: &WS) {
-        self.skip_ws = ws.1;
+        self.skip_ws = ws.1 || self.whitespace == Whitespace::Suppress;
     }
 
     /* Helper methods for writing to internal buffer */
@@ -771,14 +1708,29 @@ impl<'a, T: 'a> SetChain<'a, T> where T: cmp::Eq + hash::Hash {
     }
 }
 
+// Where a list of nodes sits in the template: blocks can be defined at the
+// top level, in other blocks and in control structures, but not in included
+// templates or macros.
+#[derive(Clone, Copy, PartialEq)]
 enum AstLevel {
     Top,
+    Block,
     Nested,
 }
 
+impl AstLevel {
+    // The level for the body of a control structure at this level
+    fn inner(self) -> AstLevel {
+        match self {
+            AstLevel::Nested => AstLevel::Nested,
+            _ => AstLevel::Block,
+        }
+    }
+}
+
 enum DisplayWrap {
     Wrapped,
     Unwrapped,
 }
 
-type MacroMap<'a> = HashMap<&'a str, &'a Macro<'a>>;
+type MacroMap<'a> = HashMap<&'a str, (FileInfo<'a>, &'a Macro<'a>)>;
@@ -0,0 +1,98 @@
+//! Runtime support for the code generated from templates
+//!
+//! The types in this module are used by the generated code, and are not
+//! meant to be used directly.
+
+use std::fmt;
+use std::iter::Peekable;
+
+use super::Result;
+
+/// Iterator adapter used for `for` loops in templates
+///
+/// Yields each item of the wrapped iterator together with a `LoopItem`
+/// describing its position, which backs the `loop` variable.
+pub struct TemplateLoop<I> where I: Iterator {
+    iter: Peekable<I>,
+    index: usize,
+}
+
+impl<I> TemplateLoop<I> where I: Iterator {
+    pub fn new(iter: I) -> TemplateLoop<I> {
+        TemplateLoop { iter: iter.peekable(), index: 0 }
+    }
+}
+
+impl<I> Iterator for TemplateLoop<I> where I: Iterator {
+    type Item = (I::Item, LoopItem);
+
+    fn next(&mut self) -> Option<(I::Item, LoopItem)> {
+        self.iter.next().map(|item| {
+            let index = self.index;
+            self.index += 1;
+            (item, LoopItem {
+                index,
+                first: index == 0,
+                last: self.iter.peek().is_none(),
+            })
+        })
+    }
+}
+
+/// Position of the current item in a template `for` loop
+#[derive(Clone, Copy)]
+pub struct LoopItem {
+    pub index: usize,
+    pub first: bool,
+    pub last: bool,
+}
+
+/// Renders a recursive `for` loop in a template
+///
+/// The loop body is generated as a closure, which renders one level of the
+/// loop. It gets a `RecursiveLoop` to render nested levels with, as
+/// `loop(children)` does in the template. All levels have the same type of
+/// iterable.
+pub fn recursive_loop<I, F>(iter: I, writer: &mut fmt::Write, body: F) -> Result<()>
+        where F: Fn(&RecursiveLoop<I>, I, &mut fmt::Write) -> Result<()> {
+    body(&RecursiveLoop { body: &body, depth: 0 }, iter, writer)
+}
+
+/// A level of a recursive `for` loop in a template
+pub struct RecursiveLoop<'a, I: 'a> {
+    body: &'a Fn(&RecursiveLoop<'a, I>, I, &mut fmt::Write) -> Result<()>,
+    /// The number of levels above this one
+    pub depth: usize,
+}
+
+impl<'a, I> RecursiveLoop<'a, I> {
+    /// Renders the loop for `iter`, one level below this one
+    pub fn recurse(&self, iter: I, writer: &mut fmt::Write) -> Result<()> {
+        (self.body)(&RecursiveLoop { body: self.body, depth: self.depth + 1 }, iter, writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TemplateLoop;
+
+    #[test]
+    fn test_template_loop() {
+        let items = TemplateLoop::new(vec!["a", "b", "c"].into_iter())
+            .map(|(s, item)| (s, item.index, item.first, item.last))
+            .collect::<Vec<_>>();
+        assert_eq!(items, vec![
+            ("a", 0, true, false),
+            ("b", 1, false, false),
+            ("c", 2, false, true),
+        ]);
+    }
+
+    #[test]
+    fn test_template_loop_single() {
+        let mut iter = TemplateLoop::new(Some(1).into_iter());
+        let (_, item) = iter.next().unwrap();
+        assert!(item.first && item.last);
+        assert!(iter.next().is_none());
+    }
+}
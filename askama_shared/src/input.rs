@@ -1,3 +1,5 @@
+use compile_error::{CompileError, FileInfo};
+use config::{self, Config, Syntax};
 use path;
 
 use std::borrow::Cow;
@@ -11,11 +13,24 @@ pub struct TemplateInput<'a> {
     pub meta: TemplateMeta<'a>,
     pub path: PathBuf,
     pub source: Cow<'a, str>,
+    pub config: &'a Config,
+    pub syntax: &'a Syntax,
+    pub dirs: Vec<PathBuf>,
 }
 
 impl<'a> TemplateInput<'a> {
-    pub fn new(ast: &'a syn::DeriveInput) -> TemplateInput<'a> {
-        let meta = TemplateMeta::new(ast);
+    pub fn new(ast: &'a syn::DeriveInput, config: &'a Config)
+               -> Result<TemplateInput<'a>, CompileError> {
+        let meta = TemplateMeta::new(ast, config)?;
+        let syntax = config.find_syntax(meta.syntax)?;
+        // Directories in the attribute take the place of the configured ones
+        let dirs = match meta.dirs {
+            Some(ref dirs) => {
+                let root = config::manifest_dir()?;
+                dirs.iter().map(|dir| root.join(dir)).collect()
+            },
+            None => config.dirs.clone(),
+        };
         let (path, source) = match meta.source {
             Source::Source(s) => {
                 let path = match meta.ext {
@@ -25,12 +40,20 @@ impl<'a> TemplateInput<'a> {
                 (path, Cow::Borrowed(s))
             },
             Source::Path(s) => {
-                let path = path::find_template_from_path(s, None);
-                let src = path::get_template_source(&path);
+                let path = path::find_template_from_path(s, None, &dirs)?;
+                let src = path::get_template_source(&path)?;
                 (path, Cow::Owned(src))
             },
         };
-        TemplateInput { ast, meta, path, source }
+        Ok(TemplateInput { ast, meta, path, source, config, syntax, dirs })
+    }
+
+    pub fn file_info(&self) -> FileInfo {
+        let path = match self.meta.source {
+            Source::Path(_) => Some(self.path.as_path()),
+            Source::Source(_) => None,
+        };
+        FileInfo { path, source: &self.source }
     }
 }
 
@@ -40,92 +63,95 @@ pub struct TemplateMeta<'a> {
     pub print: Print,
     pub escaping: EscapeMode,
     pub ext: Option<&'a str>,
+    syntax: Option<&'a str>,
+    dirs: Option<Vec<&'a str>>,
+    pub parent: Option<&'a str>,
 }
 
 impl<'a> TemplateMeta<'a> {
-    fn new(ast: &'a syn::DeriveInput) -> TemplateMeta<'a> {
-        let attr = ast.attrs.iter().find(|a| a.name() == "template");
-        if attr.is_none() {
-            let msg = format!("'template' attribute not found on struct '{}'",
-                              ast.ident.as_ref());
-            panic!(msg);
-        }
+    fn new(ast: &'a syn::DeriveInput, config: &Config)
+           -> Result<TemplateMeta<'a>, CompileError> {
+        let attr = match ast.attrs.iter().find(|a| a.name() == "template") {
+            Some(attr) => attr,
+            None => return Err(CompileError::new(format!(
+                "'template' attribute not found on struct '{}'", ast.ident.as_ref()))),
+        };
 
-        let attr = attr.unwrap();
         let mut source = None;
         let mut print = Print::None;
         let mut escaping = None;
         let mut ext = None;
+        let mut syntax = None;
+        let mut dirs = None;
+        let mut parent = None;
         if let syn::MetaItem::List(_, ref inner) = attr.value {
             for nm_item in inner {
                 if let syn::NestedMetaItem::MetaItem(ref item) = *nm_item {
                     if let syn::MetaItem::NameValue(ref key, ref val) = *item {
-                        match key.as_ref() {
-                            "path" => if let syn::Lit::Str(ref s, _) = *val {
-                                if source.is_some() {
-                                    panic!("must specify 'source' or 'path', not both");
-                                }
-                                source = Some(Source::Path(s.as_ref()));
-                            } else {
-                                panic!("template path must be string literal");
-                            },
-                            "source" => if let syn::Lit::Str(ref s, _) = *val {
+                        let key = key.as_ref();
+                        let val = match *val {
+                            syn::Lit::Str(ref s, _) => s.as_ref(),
+                            _ => return Err(CompileError::new(format!(
+                                "template {} must be a string literal", key))),
+                        };
+                        match key {
+                            "path" | "source" => {
                                 if source.is_some() {
-                                    panic!("must specify 'source' or 'path', not both");
+                                    return Err(CompileError::new(
+                                        "must specify 'source' or 'path', not both"));
                                 }
-                                source = Some(Source::Source(s.as_ref()));
-                            } else {
-                                panic!("template source must be string literal");
-                            },
-                            "print" => if let syn::Lit::Str(ref s, _) = *val {
-                                print = (s.as_ref() as &str).into();
-                            } else {
-                                panic!("print value must be string literal");
-                            },
-                            "escape" => if let syn::Lit::Str(ref s, _) = *val {
-                                escaping = Some((s.as_ref() as &str).into());
-                            } else {
-                                panic!("escape value must be string literal");
+                                source = Some(if key == "path" {
+                                    Source::Path(val)
+                                } else {
+                                    Source::Source(val)
+                                });
                             },
-                            "ext" => if let syn::Lit::Str(ref s, _) = *val {
-                                ext = Some((s.as_ref() as &str).into());
-                            } else {
-                                panic!("ext value must be string literal");
-                            },
-                            _ => { panic!("unsupported annotation key found") }
+                            "print" => { print = Print::from_name(val)?; },
+                            "escape" => { escaping = Some(EscapeMode::from_name(val)?); },
+                            "ext" => { ext = Some(val); },
+                            "syntax" => { syntax = Some(val); },
+                            "parent" => { parent = Some(val); },
+                            _ => return Err(CompileError::new(format!(
+                                "unsupported annotation key '{}' found", key))),
+                        }
+                    } else if let syn::MetaItem::List(ref key, ref items) = *item {
+                        if key.as_ref() != "dirs" {
+                            return Err(CompileError::new(format!(
+                                "unsupported annotation key '{}' found", key.as_ref())));
+                        }
+                        let mut values = Vec::new();
+                        for item in items {
+                            match *item {
+                                syn::NestedMetaItem::Literal(syn::Lit::Str(ref s, _)) => {
+                                    values.push(s.as_ref());
+                                },
+                                _ => return Err(CompileError::new(
+                                    "template dirs must be string literals")),
+                            }
                         }
+                        dirs = Some(values);
                     }
                 }
             }
         }
 
-        let source = source.expect("template path or source not found in attributes");
-        match (&source, ext.is_some()) {
-            (&Source::Path(_), true) => {
-                panic!("'ext' attribute cannot be used with 'path' attribute")
-            },
-            (&Source::Source(_), false) => {
-                panic!("must include 'ext' attribute when using 'source' attribute")
+        let source = match source {
+            Some(source) => source,
+            None => return Err(CompileError::new(
+                "template path or source not found in attributes")),
+        };
+        let ext_or_path = match (&source, ext) {
+            (&Source::Path(_), Some(_)) => return Err(CompileError::new(
+                "'ext' attribute cannot be used with 'path' attribute")),
+            (&Source::Source(_), None) => return Err(CompileError::new(
+                "must include 'ext' attribute when using 'source' attribute")),
+            (&Source::Path(p), None) => {
+                Path::new(p).extension().and_then(|s| s.to_str()).unwrap_or("")
             },
-            _ => {},
-        }
-        let escaping = match escaping {
-            Some(m) => m,
-            None => {
-                let ext = match source {
-                    Source::Path(p) => {
-                        Path::new(p).extension().map(|s| s.to_str().unwrap()).unwrap_or("")
-                    },
-                    Source::Source(_) => ext.unwrap(), // Already panicked if None
-                };
-                if HTML_EXTENSIONS.contains(&ext) {
-                    EscapeMode::Html
-                } else {
-                    EscapeMode::None
-                }
-            }
+            (&Source::Source(_), Some(ext)) => ext,
         };
-        TemplateMeta { source, print, escaping, ext }
+        let escaping = escaping.unwrap_or_else(|| config.escaping_for(ext_or_path));
+        Ok(TemplateMeta { source, print, escaping, ext, syntax, dirs, parent })
     }
 }
 
@@ -134,19 +160,18 @@ enum Source<'a> {
     Source(&'a str),
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum EscapeMode {
     Html,
     None,
 }
 
-impl<'a> From<&'a str> for EscapeMode {
-    fn from(s: &'a str) -> EscapeMode {
-        use self::EscapeMode::*;
+impl EscapeMode {
+    fn from_name(s: &str) -> Result<EscapeMode, CompileError> {
         match s {
-            "html" => Html,
-            "none" => None,
-            v => panic!("invalid value for escape option: {}", v),
+            "html" => Ok(EscapeMode::Html),
+            "none" => Ok(EscapeMode::None),
+            v => Err(CompileError::new(format!("invalid value for escape option: {}", v))),
         }
     }
 }
@@ -159,17 +184,14 @@ pub enum Print {
     None,
 }
 
-impl<'a> From<&'a str> for Print {
-    fn from(s: &'a str) -> Print {
-        use self::Print::*;
+impl Print {
+    fn from_name(s: &str) -> Result<Print, CompileError> {
         match s {
-            "all" => All,
-            "ast" => Ast,
-            "code" => Code,
-            "none" => None,
-            v => panic!("invalid value for print option: {}", v),
+            "all" => Ok(Print::All),
+            "ast" => Ok(Print::Ast),
+            "code" => Ok(Print::Code),
+            "none" => Ok(Print::None),
+            v => Err(CompileError::new(format!("invalid value for print option: {}", v))),
         }
     }
 }
-
-const HTML_EXTENSIONS: [&str; 3] = ["html", "htm", "xml"];
@@ -3,18 +3,24 @@ extern crate error_chain;
 #[macro_use]
 extern crate nom;
 extern crate quote;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate syn;
+extern crate toml;
 
-#[cfg(feature = "serde-json")]
-extern crate serde;
 #[cfg(feature = "serde-json")]
 extern crate serde_json;
 
+pub use compile_error::CompileError;
 pub use escaping::MarkupDisplay;
 pub use errors::{Error, Result};
 pub mod filters;
+pub mod helpers;
 pub mod path;
 
+mod compile_error;
+mod config;
 mod escaping;
 mod generator;
 mod input;
@@ -30,17 +36,21 @@ use input::Print;
 /// parsed, and the parse tree is fed to the code generator. Will print
 /// the parse tree and/or generated source according to the `print` key's
 /// value as passed to the `template()` attribute.
-pub fn build_template(ast: &syn::DeriveInput) -> String {
-    let data = input::TemplateInput::new(ast);
-    let nodes = parser::parse(data.source.as_ref());
+///
+/// Problems found in the template source are returned as a `CompileError`
+/// pointing at the offending location, so the caller can report them.
+pub fn build_template(ast: &syn::DeriveInput) -> ::std::result::Result<String, CompileError> {
+    let config = config::Config::new()?;
+    let data = input::TemplateInput::new(ast, &config)?;
+    let nodes = parser::parse(&data.file_info(), data.syntax)?;
     if data.meta.print == Print::Ast || data.meta.print == Print::All {
         println!("{:?}", nodes);
     }
-    let code = generator::generate(&data, &nodes);
+    let code = generator::generate(&data, &nodes)?;
     if data.meta.print == Print::Code || data.meta.print == Print::All {
         println!("{}", code);
     }
-    code
+    Ok(code)
 }
 
 mod errors {
@@ -51,3 +61,115 @@ mod errors {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+    use syn;
+
+    fn check_error(source: &str, msg: &str) {
+        let ast = syn::parse_derive_input(&format!(
+            "#[template(source = {:?}, ext = \"txt\")] struct T;", source)).unwrap();
+        match super::build_template(&ast) {
+            Ok(_) => panic!("expected an error for {:?}", source),
+            Err(err) => assert_eq!(err.to_string(), msg),
+        }
+    }
+    fn check_attr_error(attr: &str, msg: &str) {
+        let ast = syn::parse_derive_input(&format!("#[template({})] struct T;", attr)).unwrap();
+        match super::build_template(&ast) {
+            Ok(_) => panic!("expected an error for {:?}", attr),
+            Err(err) => assert_eq!(err.to_string(), msg),
+        }
+    }
+    #[test]
+    fn test_invalid_attributes() {
+        check_attr_error("source = \"\", ext = \"txt\", syntax = 1",
+                         "template syntax must be a string literal");
+        check_attr_error("source = \"\", ext = \"txt\", parent = true",
+                         "template parent must be a string literal");
+        check_attr_error("source = \"\", ext = \"txt\", dirs(\"a\", 1)",
+                         "template dirs must be string literals");
+        check_attr_error("source = \"\", ext = \"txt\", escape = \"xml\"",
+                         "invalid value for escape option: xml");
+        check_attr_error("source = \"\", ext = \"txt\", size = \"big\"",
+                         "unsupported annotation key 'size' found");
+        check_attr_error("ext = \"txt\"", "template path or source not found in attributes");
+    }
+    #[test]
+    fn test_super_in_expression() {
+        check_error("{% block a %}{{ super()|upper }}{% endblock %}",
+                    "super() can only be used on its own, as in '{{ super() }}'\n \
+                     --> <source>:1:17\n  \
+                     |\n\
+                     1 | {% block a %}{{ super()|upper }}{% endblock %}\n  \
+                     |                 ^");
+    }
+    #[test]
+    fn test_recursive_macro_through_callblock() {
+        check_error("{% macro n() %}{{ caller() }}{% endmacro %}\
+                     {% macro m() %}{% callblock n() %}{% call m() %}{% endcallblock %}{% endmacro %}\
+                     {% call m() %}",
+                    "macro 'm' calls itself, which is not supported; \
+                     use a recursive for loop to render nested data\n \
+                     --> <source>:1:86\n  \
+                     |\n\
+                     1 | {% macro n() %}{{ caller() }}{% endmacro %}\
+                     {% macro m() %}{% callblock n() %}{% call m() %}{% endcallblock %}{% endmacro %}\
+                     {% call m() %}\n  \
+                     |                                                                                      ^");
+    }
+    #[test]
+    fn test_loop_in_scoped_include() {
+        let path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("templates/loop-index.html");
+        check_error("{% for x in xs %}{% include \"loop-index.html\" with { x } %}{% endfor %}",
+                    &format!("'loop.index' is only available inside a for loop\n \
+                              --> {}:1:9\n  \
+                              |\n\
+                              1 | {{{{ loop.index }}}}\n  \
+                              |         ^", path.display()));
+    }
+    #[test]
+    fn test_loop_control_outside_loop() {
+        check_error("{% macro m() %}{% break %}{% endmacro %}{% call m() %}",
+                    "'{% break %}' is only allowed inside a for loop\n \
+                     --> <source>:1:19\n  \
+                     |\n\
+                     1 | {% macro m() %}{% break %}{% endmacro %}{% call m() %}\n  \
+                     |                   ^");
+    }
+    #[test]
+    fn test_loop_in_callblock_body() {
+        check_error("{% macro each() %}{% for i in 0..2 %}{{ caller() }}{% endfor %}{% endmacro %}\
+                     {% callblock each() %}{{ loop.index }}{% endcallblock %}",
+                    "'loop.index' is only available inside a for loop\n \
+                     --> <source>:1:108\n  \
+                     |\n\
+                     1 | {% macro each() %}{% for i in 0..2 %}{{ caller() }}{% endfor %}{% endmacro %}\
+                     {% callblock each() %}{{ loop.index }}{% endcallblock %}\n  \
+                     |                                                                                                            ^");
+    }
+    #[test]
+    fn test_break_in_callblock_body() {
+        check_error("{% macro each() %}{% for i in 0..2 %}{{ caller() }}{% endfor %}{% endmacro %}\
+                     {% for x in 0..2 %}{% callblock each() %}{% break %}{% endcallblock %}{% endfor %}",
+                    "'{% break %}' can't leave a for loop around a callblock tag from its body\n \
+                     --> <source>:1:122\n  \
+                     |\n\
+                     1 | {% macro each() %}{% for i in 0..2 %}{{ caller() }}{% endfor %}{% endmacro %}\
+                     {% for x in 0..2 %}{% callblock each() %}{% break %}{% endcallblock %}{% endfor %}\n  \
+                     |                                                                                                                          ^");
+    }
+    #[test]
+    fn test_content_after_loop_control() {
+        check_error("{% for x in xs %}{% continue %} {{ x }}{% endfor %}",
+                    "'{% continue %}' must be the last tag in its block, \
+                     because nothing after it is rendered\n \
+                     --> <source>:1:21\n  \
+                     |\n\
+                     1 | {% for x in xs %}{% continue %} {{ x }}{% endfor %}\n  \
+                     |                     ^");
+    }
+}
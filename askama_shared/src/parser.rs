@@ -1,21 +1,44 @@
+use compile_error::{CompileError, FileInfo};
+use config::Syntax;
+
 use nom::{self, IResult};
+use std::cell::Cell;
+use std::cmp;
 use std::str;
 
 #[derive(Debug)]
 pub enum Expr<'a> {
+    BoolLit(&'a str),
     NumLit(&'a str),
     StrLit(&'a str),
+    CharLit(&'a str),
     Var(&'a str),
+    Path(Vec<&'a str>),
     Attr(Box<Expr<'a>>, &'a str),
+    Index(Box<Expr<'a>>, Box<Expr<'a>>),
     Filter(&'a str, Vec<Expr<'a>>),
+    Unary(&'a str, Box<Expr<'a>>),
     BinOp(&'a str, Box<Expr<'a>>, Box<Expr<'a>>),
+    Range(&'a str, Option<Box<Expr<'a>>>, Option<Box<Expr<'a>>>),
     Group(Box<Expr<'a>>),
+    Tuple(Vec<Expr<'a>>),
+    Array(Vec<Expr<'a>>),
     MethodCall(Box<Expr<'a>>, &'a str, Vec<Expr<'a>>),
+    Call(Vec<&'a str>, Vec<Expr<'a>>),
+    NamedArg(&'a str, Box<Expr<'a>>),
 }
 
 #[derive(Debug)]
 pub enum Target<'a> {
     Name(&'a str),
+    Path(Vec<&'a str>),
+    Tuple(Vec<&'a str>, Vec<Target<'a>>),
+    Struct(Vec<&'a str>, Vec<(&'a str, Target<'a>)>),
+    BoolLit(&'a str),
+    NumLit(&'a str),
+    StrLit(&'a str),
+    CharLit(&'a str),
+    Wildcard,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -24,29 +47,49 @@ pub struct WS(pub bool, pub bool);
 #[derive(Debug)]
 pub struct Macro<'a> {
     pub ws1: WS,
-    pub args: Vec<&'a str>,
+    pub args: Vec<(&'a str, Option<Expr<'a>>)>,
     pub nodes: Vec<Node<'a>>,
     pub ws2: WS,
 }
 
+#[derive(Debug)]
+pub struct Loop<'a> {
+    pub ws1: WS,
+    pub var: Target<'a>,
+    pub iter: Expr<'a>,
+    pub cond: Option<Expr<'a>>,
+    pub body: Vec<Node<'a>>,
+    pub ws2: WS,
+    pub else_block: Vec<Node<'a>>,
+    pub ws3: WS,
+    pub recursive: bool,
+}
+
 #[derive(Debug)]
 pub enum Node<'a> {
     Lit(&'a str, &'a str, &'a str),
     Comment(),
     Expr(WS, Expr<'a>),
-    Call(WS, &'a str, Vec<Expr<'a>>),
+    Call(WS, Option<&'a str>, &'a str, Vec<Expr<'a>>, Option<Macro<'a>>),
     LetDecl(WS, Target<'a>),
     Let(WS, Target<'a>, Expr<'a>),
     Cond(Vec<(WS, Option<Expr<'a>>, Vec<Node<'a>>)>, WS),
-    Loop(WS, Target<'a>, Expr<'a>, Vec<Node<'a>>, WS),
+    Loop(Loop<'a>),
+    Break(WS, &'a str),
+    Continue(WS, &'a str),
+    Match(WS, Expr<'a>, Vec<When<'a>>, WS),
     Extends(Expr<'a>),
     BlockDef(WS, &'a str, Vec<Node<'a>>, WS),
-    Include(WS, &'a str),
+    Include(WS, &'a str, bool, Option<Vec<(&'a str, Expr<'a>)>>),
+    Import(WS, &'a str, &'a str),
     Macro(&'a str, Macro<'a>),
+    Raw(WS, &'a str, &'a str, &'a str, WS),
 }
 
 pub type Cond<'a> = (WS, Option<Expr<'a>>, Vec<Node<'a>>);
 
+pub type When<'a> = (WS, Target<'a>, Vec<Node<'a>>);
+
 fn split_ws_parts(s: &[u8]) -> Node {
     if s.is_empty() {
         let rs = str::from_utf8(s).unwrap();
@@ -73,75 +116,308 @@ fn split_ws_parts(s: &[u8]) -> Node {
               str::from_utf8(res.2).unwrap())
 }
 
-enum ContentState {
-    Any,
-    Brace(usize),
-    End(usize),
-}
-
-fn take_content(i: &[u8]) -> IResult<&[u8], Node> {
-    use parser::ContentState::*;
-    let mut state = Any;
-    for (idx, c) in i.iter().enumerate() {
-        state = match (state, *c) {
-            (Any, b'{') => Brace(idx),
-            (Brace(start), b'{') |
-            (Brace(start), b'%') |
-            (Brace(start), b'#') => End(start),
-            (Any, _) |
-            (Brace(_), _) => Any,
-            (End(_), _) => panic!("cannot happen"),
-        };
-        if let End(_) = state {
-            break;
-        }
-    }
-    match state {
-        Any |
-        Brace(_) => IResult::Done(&i[..0], split_ws_parts(i)),
-        End(0) => IResult::Error(nom::ErrorKind::Custom(0)),
-        End(start) => IResult::Done(&i[start..], split_ws_parts(&i[..start])),
+fn take_content<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    let starts = [&s.block_start, &s.expr_start, &s.comment_start];
+    let end = (0..i.len()).find(|&idx| {
+        starts.iter().any(|start| i[idx..].starts_with(start.as_bytes()))
+    });
+    match end {
+        None => IResult::Done(&i[..0], split_ws_parts(i)),
+        Some(0) => IResult::Error(nom::ErrorKind::Custom(0)),
+        Some(end) => IResult::Done(&i[end..], split_ws_parts(&i[..end])),
     }
 }
 
+thread_local! {
+    // The length of the shortest input left by some parser, which tells how
+    // far parsing got in the current template. Parse errors are reported
+    // there, rather than at the start of the outermost node that failed.
+    static FURTHEST: Cell<usize> = Cell::new(usize::max_value());
+}
+
+// Records that parsing got as far as the start of `input`.
+fn reached(input: &[u8]) {
+    FURTHEST.with(|furthest| if input.len() < furthest.get() {
+        furthest.set(input.len());
+    });
+}
+
 fn identifier(input: &[u8]) -> IResult<&[u8], &str> {
-    if !nom::is_alphabetic(input[0]) && input[0] != b'_' {
-        return IResult::Error(nom::ErrorKind::Custom(0));
+    match input.first() {
+        None => return IResult::Incomplete(nom::Needed::Size(1)),
+        Some(&c) if !nom::is_alphabetic(c) && c != b'_' => {
+            return IResult::Error(nom::ErrorKind::Custom(0));
+        },
+        _ => {},
     }
     for (i, ch) in input.iter().enumerate() {
         if i == 0 || nom::is_alphanumeric(*ch) || *ch == b'_' {
             continue;
         }
+        reached(&input[i..]);
         return IResult::Done(&input[i..],
                              str::from_utf8(&input[..i]).unwrap());
     }
+    reached(&input[1..]);
     IResult::Done(&input[1..], str::from_utf8(&input[..1]).unwrap())
 }
 
-named!(expr_num_lit<Expr>, map!(nom::digit,
-    |s| Expr::NumLit(str::from_utf8(s).unwrap())
+// Returns the length of the escape sequence at the start of `input`, which
+// follows a backslash, if it is valid in a Rust string or char literal.
+fn escape_len(input: &[u8]) -> Option<usize> {
+    match input.first() {
+        Some(&b'n') | Some(&b'r') | Some(&b't') | Some(&b'0') |
+        Some(&b'\\') | Some(&b'\'') | Some(&b'"') => Some(1),
+        Some(&b'x') if input.len() >= 3 && (input[1] as char).is_digit(8) &&
+                       (input[2] as char).is_digit(16) => Some(3),
+        Some(&b'u') if input.get(1) == Some(&b'{') => {
+            let digits = input[2..].iter().take_while(|c| (**c as char).is_digit(16)).count();
+            if digits >= 1 && digits <= 6 && input.get(2 + digits) == Some(&b'}') {
+                Some(3 + digits)
+            } else {
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+// Parses a literal delimited by `quote`, returning the literal as written
+// (including the quotes) and the number of characters it contains.
+fn quoted(input: &[u8], quote: u8) -> IResult<&[u8], (&str, usize)> {
+    if input.first() != Some(&quote) {
+        return IResult::Error(nom::ErrorKind::Custom(0));
+    }
+    let mut chars = 0;
+    let mut i = 1;
+    while i < input.len() {
+        let c = input[i];
+        if c == quote {
+            let lit = str::from_utf8(&input[..i + 1]).unwrap();
+            return IResult::Done(&input[i + 1..], (lit, chars));
+        } else if c == b'\\' {
+            match escape_len(&input[i + 1..]) {
+                Some(len) => { i += len + 1; },
+                None => {
+                    reached(&input[i..]);
+                    return IResult::Error(nom::ErrorKind::Custom(0));
+                },
+            }
+            chars += 1;
+        } else {
+            // Don't count UTF-8 continuation bytes as characters
+            if c & 0xC0 != 0x80 {
+                chars += 1;
+            }
+            i += 1;
+        }
+    }
+    IResult::Incomplete(nom::Needed::Unknown)
+}
+
+// Parses a raw string literal like `r"foo"` or `r#"foo"#`.
+fn raw_str_lit(input: &[u8]) -> IResult<&[u8], &str> {
+    if input.first() != Some(&b'r') {
+        return IResult::Error(nom::ErrorKind::Custom(0));
+    }
+    let hashes = input[1..].iter().take_while(|c| **c == b'#').count();
+    if input.get(hashes + 1) != Some(&b'"') {
+        return IResult::Error(nom::ErrorKind::Custom(0));
+    }
+    let mut closing = vec![b'"'];
+    closing.extend(vec![b'#'; hashes]);
+    let start = hashes + 2;
+    match input[start..].windows(closing.len()).position(|w| w == &closing[..]) {
+        Some(pos) => {
+            let end = start + pos + closing.len();
+            IResult::Done(&input[end..], str::from_utf8(&input[..end]).unwrap())
+        },
+        None => IResult::Incomplete(nom::Needed::Unknown),
+    }
+}
+
+// String literals may be double-quoted, single-quoted or raw, and are kept
+// as written. A single-quoted literal containing exactly one character is
+// a char literal instead.
+fn str_lit(input: &[u8]) -> IResult<&[u8], &str> {
+    let quote = match input.first() {
+        Some(&b'r') => return raw_str_lit(input),
+        Some(&b'"') => b'"',
+        Some(&b'\'') => b'\'',
+        _ => return IResult::Error(nom::ErrorKind::Custom(0)),
+    };
+    match quoted(input, quote) {
+        IResult::Done(_, (_, 1)) if quote == b'\'' => IResult::Error(nom::ErrorKind::Custom(0)),
+        IResult::Done(rest, (lit, _)) => IResult::Done(rest, lit),
+        IResult::Error(err) => IResult::Error(err),
+        IResult::Incomplete(needed) => IResult::Incomplete(needed),
+    }
+}
+
+fn char_lit(input: &[u8]) -> IResult<&[u8], &str> {
+    match quoted(input, b'\'') {
+        IResult::Done(rest, (lit, 1)) => IResult::Done(rest, lit),
+        IResult::Done(..) => IResult::Error(nom::ErrorKind::Custom(0)),
+        IResult::Error(err) => IResult::Error(err),
+        IResult::Incomplete(needed) => IResult::Incomplete(needed),
+    }
+}
+
+named!(bool_lit<&str>, map_opt!(identifier,
+    |s| match s {
+        "true" | "false" => Some(s),
+        _ => None,
+    }
+));
+
+named!(num_lit<&str>, map!(
+    recognize!(pair!(nom::digit, opt!(complete!(pair!(char!('.'), nom::digit))))),
+    |s| str::from_utf8(s).unwrap()
+));
+
+named!(expr_bool_lit<Expr>, map!(bool_lit,
+    |s| Expr::BoolLit(s)
+));
+
+named!(expr_num_lit<Expr>, map!(num_lit,
+    |s| Expr::NumLit(s)
 ));
 
-named!(expr_str_lit<Expr>, map!(
-    delimited!(char!('"'), is_not!("\""), char!('"')),
-    |s| Expr::StrLit(str::from_utf8(s).unwrap())
+named!(expr_str_lit<Expr>, map!(str_lit,
+    |s| Expr::StrLit(s)
+));
+
+named!(expr_char_lit<Expr>, map!(char_lit,
+    |s| Expr::CharLit(s)
 ));
 
 named!(expr_var<Expr>, map!(identifier,
     |s| Expr::Var(s))
 );
 
-named!(target_single<Target>, map!(identifier,
-    |s| Target::Name(s)
+named!(path<Vec<&str>>, do_parse!(
+    start: identifier >>
+    rest: many0!(preceded!(tag_s!("::"), identifier)) >>
+    ({
+        let mut res = vec![start];
+        res.extend(rest);
+        res
+    })
+));
+
+named!(target_tuple_fields<Vec<Target>>, delimited!(
+    tag_s!("("),
+    separated_list!(tag_s!(","), ws!(target)),
+    tag_s!(")")
+));
+
+named!(target_struct_field<(&str, Target)>, do_parse!(
+    name: ws!(identifier) >>
+    target: opt!(preceded!(tag_s!(":"), ws!(target))) >>
+    (name, target.unwrap_or_else(|| Target::Name(name)))
+));
+
+named!(target_struct_fields<Vec<(&str, Target)>>, delimited!(
+    tag_s!("{"),
+    separated_list!(tag_s!(","), target_struct_field),
+    tag_s!("}")
+));
+
+named!(target_path<Target>, do_parse!(
+    path: path >>
+    tuple: opt!(target_tuple_fields) >>
+    fields: cond!(tuple.is_none(), opt!(ws!(target_struct_fields))) >>
+    (match (tuple, fields) {
+        (Some(targets), _) => Target::Tuple(path, targets),
+        (_, Some(Some(fields))) => Target::Struct(path, fields),
+        _ => if path.len() > 1 || path[0].starts_with(char::is_uppercase) {
+            Target::Path(path)
+        } else if path[0] == "_" {
+            Target::Wildcard
+        } else {
+            Target::Name(path[0])
+        },
+    })
+));
+
+named!(target_num_lit<&str>, map!(
+    recognize!(pair!(opt!(tag_s!("-")), num_lit)),
+    |s| str::from_utf8(s).unwrap()
+));
+
+named!(target<Target>, alt!(
+    map!(bool_lit, |s| Target::BoolLit(s)) |
+    map!(target_num_lit, |s| Target::NumLit(s)) |
+    map!(str_lit, |s| Target::StrLit(s)) |
+    map!(char_lit, |s| Target::CharLit(s)) |
+    map!(target_tuple_fields, |targets| Target::Tuple(Vec::new(), targets)) |
+    target_path
+));
+
+// The patterns of `let` and `for` have to bind names: unlike in `when` arms,
+// literals and plain paths (like `Foo` or `a::b`) are not accepted.
+named!(binding_tuple_fields<Vec<Target>>, delimited!(
+    tag_s!("("),
+    separated_list!(tag_s!(","), ws!(binding)),
+    tag_s!(")")
+));
+
+named!(binding_struct_field<(&str, Target)>, do_parse!(
+    name: ws!(identifier) >>
+    target: opt!(preceded!(tag_s!(":"), ws!(binding))) >>
+    (name, target.unwrap_or_else(|| Target::Name(name)))
+));
+
+named!(binding_struct_fields<Vec<(&str, Target)>>, delimited!(
+    tag_s!("{"),
+    separated_list!(tag_s!(","), binding_struct_field),
+    tag_s!("}")
+));
+
+named!(binding_path<Target>, map_opt!(do_parse!(
+    path: path >>
+    tuple: opt!(binding_tuple_fields) >>
+    fields: cond!(tuple.is_none(), opt!(ws!(binding_struct_fields))) >>
+    (path, tuple, fields)
+), |(path, tuple, fields): (Vec<&'a str>, _, _)| match (tuple, fields) {
+    (Some(targets), _) => Some(Target::Tuple(path, targets)),
+    (_, Some(Some(fields))) => Some(Target::Struct(path, fields)),
+    _ => if path.len() > 1 || path[0].starts_with(char::is_uppercase) {
+        None
+    } else if path[0] == "_" {
+        Some(Target::Wildcard)
+    } else {
+        Some(Target::Name(path[0]))
+    },
+}));
+
+named!(binding<Target>, alt!(
+    map!(binding_tuple_fields, |targets| Target::Tuple(Vec::new(), targets)) |
+    binding_path
+));
+
+// Named arguments (`name = value`) are only valid in macro calls, which is
+// checked by the code generator.
+named!(named_argument<Expr>, do_parse!(
+    name: identifier >>
+    ws!(tag_s!("=")) >>
+    not!(tag_s!("=")) >>
+    value: expr_any >>
+    (Expr::NamedArg(name, Box::new(value)))
+));
+
+named!(argument<Expr>, alt!(
+    named_argument |
+    expr_any
 ));
 
 named!(arguments<Vec<Expr>>, do_parse!(
     tag_s!("(") >>
     args: opt!(do_parse!(
-        arg0: ws!(expr_any) >>
+        arg0: ws!(argument) >>
         args: many0!(do_parse!(
             tag_s!(",") >>
-            argn: ws!(expr_any) >>
+            argn: ws!(argument) >>
             (argn)
         )) >>
         ({
@@ -154,13 +430,19 @@ named!(arguments<Vec<Expr>>, do_parse!(
     (args.unwrap_or_default())
 ));
 
-named!(parameters<Vec<&'a str>>, do_parse!(
+named!(parameter<(&str, Option<Expr>)>, do_parse!(
+    name: identifier >>
+    default: opt!(preceded!(ws!(tag_s!("=")), expr_any)) >>
+    ((name, default))
+));
+
+named!(parameters<Vec<(&str, Option<Expr>)>>, do_parse!(
     tag_s!("(") >>
     vals: opt!(do_parse!(
-        arg0: ws!(identifier) >>
+        arg0: ws!(parameter) >>
         args: many0!(do_parse!(
             tag_s!(",") >>
-            argn: ws!(identifier) >>
+            argn: ws!(parameter) >>
             (argn)
         )) >>
         ({
@@ -173,35 +455,88 @@ named!(parameters<Vec<&'a str>>, do_parse!(
     (vals.unwrap_or_default())
 ));
 
-named!(expr_group<Expr>, map!(
-    delimited!(char!('('), expr_any, char!(')')),
-    |s| Expr::Group(Box::new(s))
+// A single parenthesized expression without a trailing comma is a group,
+// anything else is a tuple.
+named!(expr_group<Expr>, do_parse!(
+    tag_s!("(") >>
+    items: separated_list!(tag_s!(","), ws!(expr_any)) >>
+    trailing: opt!(ws!(tag_s!(","))) >>
+    ws!(tag_s!(")")) >>
+    ({
+        let mut items = items;
+        if items.len() == 1 && trailing.is_none() {
+            Expr::Group(Box::new(items.pop().unwrap()))
+        } else {
+            Expr::Tuple(items)
+        }
+    })
+));
+
+named!(expr_array<Expr>, do_parse!(
+    tag_s!("[") >>
+    items: separated_list!(tag_s!(","), ws!(expr_any)) >>
+    opt!(ws!(tag_s!(","))) >>
+    ws!(tag_s!("]")) >>
+    (Expr::Array(items))
+));
+
+named!(expr_path<Expr>, map_opt!(path, |path: Vec<&'a str>| {
+    if path.len() > 1 {
+        Some(Expr::Path(path))
+    } else {
+        None
+    }
+}));
+
+named!(expr_call<Expr>, do_parse!(
+    path: path >>
+    args: arguments >>
+    (Expr::Call(path, args))
 ));
 
 named!(expr_single<Expr>, alt!(
+    expr_bool_lit |
     expr_num_lit |
     expr_str_lit |
+    expr_char_lit |
+    expr_call |
+    expr_path |
     expr_var |
-    expr_group
+    expr_group |
+    expr_array
 ));
 
-named!(attr<(&str, Option<Vec<Expr>>)>, do_parse!(
+enum Suffix<'a> {
+    Attr(&'a str, Option<Vec<Expr<'a>>>),
+    Index(Expr<'a>),
+}
+
+named!(attr<Suffix>, do_parse!(
     tag_s!(".") >>
     attr: identifier >>
     args: opt!(arguments) >>
-    (attr, args)
+    (Suffix::Attr(attr, args))
+));
+
+named!(index<Suffix>, do_parse!(
+    tag_s!("[") >>
+    key: ws!(expr_any) >>
+    tag_s!("]") >>
+    (Suffix::Index(key))
 ));
 
 named!(expr_attr<Expr>, do_parse!(
     obj: expr_single >>
-    attrs: many0!(attr) >>
+    suffixes: many0!(alt!(attr | index)) >>
     ({
         let mut res = obj;
-        for (aname, args) in attrs {
-            res = if args.is_some() {
-                Expr::MethodCall(Box::new(res), aname, args.unwrap())
-            } else {
-                Expr::Attr(Box::new(res), aname)
+        for suffix in suffixes {
+            res = match suffix {
+                Suffix::Attr(aname, Some(args)) => {
+                    Expr::MethodCall(Box::new(res), aname, args)
+                },
+                Suffix::Attr(aname, None) => Expr::Attr(Box::new(res), aname),
+                Suffix::Index(key) => Expr::Index(Box::new(res), Box::new(key)),
             };
         }
         res
@@ -234,22 +569,34 @@ named!(expr_filtered<Expr>, do_parse!(
     })
 ));
 
+named!(expr_unary<Expr>, alt!(
+    do_parse!(
+        op: alt!(tag_s!("!") | tag_s!("-")) >>
+        expr: ws!(expr_unary) >>
+        (Expr::Unary(str::from_utf8(op).unwrap(), Box::new(expr)))
+    ) |
+    expr_filtered
+));
+
 macro_rules! expr_prec_layer {
     ( $name:ident, $inner:ident, $( $op:expr ),* ) => {
         named!($name<Expr>, do_parse!(
             left: $inner >>
-            op_and_right: opt!(pair!(ws!(alt!($( tag_s!($op) )|*)), expr_any)) >>
-            (match op_and_right {
-                Some((op, right)) => Expr::BinOp(
-                    str::from_utf8(op).unwrap(), Box::new(left), Box::new(right)
-                ),
-                None => left,
+            rest: many0!(pair!(ws!(alt!($( tag_s!($op) )|*)), $inner)) >>
+            ({
+                let mut res = left;
+                for (op, right) in rest {
+                    res = Expr::BinOp(
+                        str::from_utf8(op).unwrap(), Box::new(res), Box::new(right)
+                    );
+                }
+                res
             })
         ));
     }
 }
 
-expr_prec_layer!(expr_muldivmod, expr_filtered, "*", "/", "%");
+expr_prec_layer!(expr_muldivmod, expr_unary, "*", "/", "%");
 expr_prec_layer!(expr_addsub, expr_muldivmod, "+", "-");
 expr_prec_layer!(expr_shifts, expr_addsub, ">>", "<<");
 expr_prec_layer!(expr_band, expr_shifts, "&");
@@ -259,66 +606,149 @@ expr_prec_layer!(expr_compare, expr_bor,
     "==", "!=", ">=", ">", "<=", "<"
 );
 expr_prec_layer!(expr_and, expr_compare, "&&");
-expr_prec_layer!(expr_any, expr_and, "||");
+expr_prec_layer!(expr_or, expr_and, "||");
 
-named!(expr_node<Node>, do_parse!(
-    tag_s!("{{") >>
-    pws: opt!(tag_s!("-")) >>
-    expr: ws!(expr_any) >>
-    nws: opt!(tag_s!("-")) >>
-    tag_s!("}}") >>
-    (Node::Expr(WS(pws.is_some(), nws.is_some()), expr))
+named!(range_right<(&str, Option<Expr>)>, do_parse!(
+    op: ws!(alt!(tag_s!("..=") | tag_s!(".."))) >>
+    right: opt!(expr_or) >>
+    (str::from_utf8(op).unwrap(), right)
+));
+
+named!(expr_any<Expr>, alt!(
+    map!(range_right, |(op, right)| Expr::Range(op, None, right.map(Box::new))) |
+    do_parse!(
+        left: expr_or >>
+        rest: opt!(range_right) >>
+        (match rest {
+            Some((op, right)) => Expr::Range(op, Some(Box::new(left)), right.map(Box::new)),
+            None => left,
+        })
+    )
 ));
 
+fn expr_node<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        tag_s!(s.expr_start.as_str()) >>
+        pws: opt!(tag_s!("-")) >>
+        expr: ws!(expr_any) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(s.expr_end.as_str()) >>
+        (Node::Expr(WS(pws.is_some(), nws.is_some()), expr))
+    )
+}
+
 named!(block_call<Node>, do_parse!(
     pws: opt!(tag_s!("-")) >>
     ws!(tag_s!("call")) >>
+    scope: opt!(ws!(terminated!(identifier, tag_s!("::")))) >>
     name: ws!(identifier) >>
     args: ws!(arguments) >>
     nws: opt!(tag_s!("-")) >>
-    (Node::Call(WS(pws.is_some(), nws.is_some()), name, args))
+    (Node::Call(WS(pws.is_some(), nws.is_some()), scope, name, args, None))
 ));
 
+// A `callblock` tag has a body up to the matching `endcallblock`, which is
+// passed to the macro like an anonymous macro that it can call as `caller()`.
+fn block_callblock<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("callblock")) >>
+        params: opt!(ws!(parameters)) >>
+        scope: opt!(ws!(terminated!(identifier, tag_s!("::")))) >>
+        name: ws!(identifier) >>
+        args: ws!(arguments) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(s.block_start.as_str()) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endcallblock")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::Call(WS(pws1.is_some(), nws2.is_some()), scope, name, args, Some(Macro {
+            ws1: WS(pws1.is_some(), nws1.is_some()),
+            args: params.unwrap_or_default(),
+            nodes: contents,
+            ws2: WS(pws2.is_some(), nws2.is_some()),
+        })))
+    )
+}
+
 named!(cond_if<Expr>, do_parse!(
     ws!(tag_s!("if")) >>
     cond: ws!(expr_any) >>
     (cond)
 ));
 
-named!(cond_block<Cond>, do_parse!(
-    tag_s!("{%") >>
-    pws: opt!(tag_s!("-")) >>
-    ws!(tag_s!("else")) >>
-    cond: opt!(cond_if) >>
-    nws: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    block: parse_template >>
-    (WS(pws.is_some(), nws.is_some()), cond, block)
-));
-
-named!(block_if<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    cond: ws!(cond_if) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    block: parse_template >>
-    elifs: many0!(cond_block) >>
-    tag_s!("{%") >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endif")) >>
-    nws2: opt!(tag_s!("-")) >>
-    ({
-       let mut res = Vec::new();
-       res.push((WS(pws1.is_some(), nws1.is_some()), Some(cond), block));
-       res.extend(elifs);
-       Node::Cond(res, WS(pws2.is_some(), nws2.is_some()))
-    })
-));
+fn cond_block<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Cond<'a>> {
+    do_parse!(i,
+        tag_s!(s.block_start.as_str()) >>
+        pws: opt!(tag_s!("-")) >>
+        ws!(tag_s!("else")) >>
+        cond: opt!(cond_if) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        block: call!(parse_template, s) >>
+        (WS(pws.is_some(), nws.is_some()), cond, block)
+    )
+}
+
+fn block_if<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        cond: ws!(cond_if) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        block: call!(parse_template, s) >>
+        elifs: many0!(call!(cond_block, s)) >>
+        tag_s!(s.block_start.as_str()) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endif")) >>
+        nws2: opt!(tag_s!("-")) >>
+        ({
+           let mut res = Vec::new();
+           res.push((WS(pws1.is_some(), nws1.is_some()), Some(cond), block));
+           res.extend(elifs);
+           Node::Cond(res, WS(pws2.is_some(), nws2.is_some()))
+        })
+    )
+}
+
+fn when_block<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], When<'a>> {
+    do_parse!(i,
+        tag_s!(s.block_start.as_str()) >>
+        pws: opt!(tag_s!("-")) >>
+        ws!(tag_s!("when")) >>
+        target: ws!(target) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        block: call!(parse_template, s) >>
+        (WS(pws.is_some(), nws.is_some()), target, block)
+    )
+}
+
+fn block_match<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("match")) >>
+        expr: ws!(expr_any) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        opt!(nom::multispace) >>
+        arms: many1!(call!(when_block, s)) >>
+        tag_s!(s.block_start.as_str()) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endmatch")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::Match(WS(pws1.is_some(), nws1.is_some()),
+                     expr, arms,
+                     WS(pws2.is_some(), nws2.is_some())))
+    )
+}
 
 named!(block_let<Node>, do_parse!(
     pws: opt!(tag_s!("-")) >>
     ws!(tag_s!("let")) >>
-    var: ws!(target_single) >>
+    var: ws!(binding) >>
     val: opt!(do_parse!(
         ws!(tag_s!("=")) >>
         val: ws!(expr_any) >>
@@ -332,22 +762,65 @@ named!(block_let<Node>, do_parse!(
     })
 ));
 
-named!(block_for<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    ws!(tag_s!("for")) >>
-    var: ws!(target_single) >>
-    ws!(tag_s!("in")) >>
-    iter: ws!(expr_any) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    block: parse_template >>
-    tag_s!("{%") >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endfor")) >>
-    nws2: opt!(tag_s!("-")) >>
-    (Node::Loop(WS(pws1.is_some(), nws1.is_some()),
-                var, iter, block,
-                WS(pws2.is_some(), pws2.is_some())))
+fn loop_else<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], (WS, Vec<Node<'a>>)> {
+    do_parse!(i,
+        tag_s!(s.block_start.as_str()) >>
+        pws: opt!(tag_s!("-")) >>
+        ws!(tag_s!("else")) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        block: call!(parse_template, s) >>
+        (WS(pws.is_some(), nws.is_some()), block)
+    )
+}
+
+fn block_for<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("for")) >>
+        var: ws!(binding) >>
+        ws!(tag_s!("in")) >>
+        iter: ws!(expr_any) >>
+        cond: opt!(cond_if) >>
+        recursive: opt!(ws!(tag_s!("recursive"))) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        body: call!(parse_template, s) >>
+        else_block: opt!(call!(loop_else, s)) >>
+        tag_s!(s.block_start.as_str()) >>
+        pws3: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endfor")) >>
+        nws3: opt!(tag_s!("-")) >>
+        ({
+            let ws3 = WS(pws3.is_some(), nws3.is_some());
+            let (ws2, else_block) = else_block.unwrap_or_else(|| (ws3, Vec::new()));
+            Node::Loop(Loop {
+                ws1: WS(pws1.is_some(), nws1.is_some()),
+                var,
+                iter,
+                cond,
+                body,
+                ws2,
+                else_block,
+                ws3,
+                recursive: recursive.is_some(),
+            })
+        })
+    )
+}
+
+named!(block_break<Node>, do_parse!(
+    pws: opt!(tag_s!("-")) >>
+    keyword: map!(ws!(tag_s!("break")), |s| str::from_utf8(s).unwrap()) >>
+    nws: opt!(tag_s!("-")) >>
+    (Node::Break(WS(pws.is_some(), nws.is_some()), keyword))
+));
+
+named!(block_continue<Node>, do_parse!(
+    pws: opt!(tag_s!("-")) >>
+    keyword: map!(ws!(tag_s!("continue")), |s| str::from_utf8(s).unwrap()) >>
+    nws: opt!(tag_s!("-")) >>
+    (Node::Continue(WS(pws.is_some(), nws.is_some()), keyword))
 ));
 
 named!(block_extends<Node>, do_parse!(
@@ -356,104 +829,217 @@ named!(block_extends<Node>, do_parse!(
     (Node::Extends(name))
 ));
 
-named!(block_block<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    ws!(tag_s!("block")) >>
+fn block_block<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("block")) >>
+        name: ws!(identifier) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(s.block_start.as_str()) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endblock")) >>
+        opt!(ws!(tag_s!(name))) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::BlockDef(WS(pws1.is_some(), nws1.is_some()),
+                        name, contents,
+                        WS(pws2.is_some(), nws2.is_some())))
+    )
+}
+
+named!(include_var<(&str, Expr)>, do_parse!(
     name: ws!(identifier) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    contents: parse_template >>
-    tag_s!("{%") >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endblock")) >>
-    opt!(ws!(tag_s!(name))) >>
-    nws2: opt!(tag_s!("-")) >>
-    (Node::BlockDef(WS(pws1.is_some(), nws1.is_some()),
-                    name, contents,
-                    WS(pws2.is_some(), pws2.is_some())))
+    value: opt!(preceded!(tag_s!(":"), ws!(expr_any))) >>
+    (name, value.unwrap_or_else(|| Expr::Var(name)))
+));
+
+named!(include_context<Vec<(&str, Expr)>>, do_parse!(
+    ws!(tag_s!("with")) >>
+    vars: delimited!(
+        tag_s!("{"),
+        separated_list!(tag_s!(","), include_var),
+        tag_s!("}")
+    ) >>
+    (vars)
 ));
 
 named!(block_include<Node>, do_parse!(
     pws: opt!(tag_s!("-")) >>
     ws!(tag_s!("include")) >>
     name: ws!(expr_str_lit) >>
+    ignore_missing: opt!(complete!(do_parse!(
+        ws!(tag_s!("ignore")) >>
+        ws!(tag_s!("missing")) >>
+        (())
+    ))) >>
+    context: opt!(complete!(ws!(include_context))) >>
     nws: opt!(tag_s!("-")) >>
     (Node::Include(WS(pws.is_some(), nws.is_some()), match name {
         Expr::StrLit(s) => s,
         _ => panic!("include path must be a string literal"),
-    }))
+    }, ignore_missing.is_some(), context))
 ));
 
-named!(block_macro<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    ws!(tag_s!("macro")) >>
-    name: ws!(identifier) >>
-    params: ws!(parameters) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    contents: parse_template >>
-    tag_s!("{%") >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endmacro")) >>
-    nws2: opt!(tag_s!("-")) >>
-    (Node::Macro(
-         name,
-         Macro {
-             ws1: WS(pws1.is_some(), nws1.is_some()),
-             args: params,
-             nodes: contents,
-             ws2: WS(pws2.is_some(), nws2.is_some())
-         }
-    ))
-));
-
-named!(block_node<Node>, do_parse!(
-    tag_s!("{%") >>
-    contents: alt!(
-        block_call |
-        block_let |
-        block_if |
-        block_for |
-        block_extends |
-        block_include |
-        block_block |
-        block_macro
-    ) >>
-    tag_s!("%}") >>
-    (contents)
+named!(block_import<Node>, do_parse!(
+    pws: opt!(tag_s!("-")) >>
+    ws!(tag_s!("import")) >>
+    name: ws!(expr_str_lit) >>
+    ws!(tag_s!("as")) >>
+    scope: ws!(identifier) >>
+    nws: opt!(tag_s!("-")) >>
+    (Node::Import(WS(pws.is_some(), nws.is_some()), match name {
+        Expr::StrLit(s) => s,
+        _ => panic!("import path must be a string literal"),
+    }, scope))
 ));
 
-named!(block_comment<Node>, do_parse!(
-    tag_s!("{#") >>
-    take_until_s!("#}") >>
-    tag_s!("#}") >>
-    (Node::Comment())
-));
+fn block_macro<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("macro")) >>
+        name: ws!(identifier) >>
+        params: ws!(parameters) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(s.block_start.as_str()) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endmacro")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::Macro(
+             name,
+             Macro {
+                 ws1: WS(pws1.is_some(), nws1.is_some()),
+                 args: params,
+                 nodes: contents,
+                 ws2: WS(pws2.is_some(), nws2.is_some())
+             }
+        ))
+    )
+}
 
-named!(parse_template<Vec<Node<'a>>>, many0!(alt!(
-    take_content |
-    block_comment |
-    expr_node |
-    block_node
-)));
+fn endraw<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], ()> {
+    do_parse!(i,
+        tag_s!(s.block_start.as_str()) >>
+        opt!(tag_s!("-")) >>
+        ws!(tag_s!("endraw")) >>
+        opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        ()
+    )
+}
 
-pub fn parse(src: &str) -> Vec<Node> {
-    match parse_template(src.as_bytes()) {
+// Takes everything up to the next `endraw` tag, ignoring other tags.
+fn raw_content<'a>(input: &'a [u8], s: &Syntax) -> IResult<&'a [u8], &'a [u8]> {
+    let tag = s.block_start.as_bytes();
+    let mut start = 0;
+    while let Some(pos) = input[start..].windows(tag.len()).position(|w| w == tag) {
+        let idx = start + pos;
+        if let IResult::Done(..) = endraw(&input[idx..], s) {
+            return IResult::Done(&input[idx..], &input[..idx]);
+        }
+        start = idx + tag.len();
+    }
+    IResult::Incomplete(nom::Needed::Unknown)
+}
+
+fn block_raw<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("raw")) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(s.block_end.as_str()) >>
+        contents: call!(raw_content, s) >>
+        tag_s!(s.block_start.as_str()) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endraw")) >>
+        nws2: opt!(tag_s!("-")) >>
+        ({
+            let (lws, val, rws) = match split_ws_parts(contents) {
+                Node::Lit(lws, val, rws) => (lws, val, rws),
+                _ => unreachable!(),
+            };
+            Node::Raw(WS(pws1.is_some(), nws1.is_some()), lws, val, rws,
+                      WS(pws2.is_some(), nws2.is_some()))
+        })
+    )
+}
+
+fn block_node<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        tag_s!(s.block_start.as_str()) >>
+        contents: alt!(
+            call!(block_callblock, s) |
+            block_call |
+            block_let |
+            call!(block_if, s) |
+            call!(block_match, s) |
+            call!(block_for, s) |
+            block_break |
+            block_continue |
+            block_extends |
+            block_include |
+            block_import |
+            call!(block_block, s) |
+            call!(block_macro, s) |
+            call!(block_raw, s)
+        ) >>
+        tag_s!(s.block_end.as_str()) >>
+        (contents)
+    )
+}
+
+fn block_comment<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        tag_s!(s.comment_start.as_str()) >>
+        take_until_s!(s.comment_end.as_str()) >>
+        tag_s!(s.comment_end.as_str()) >>
+        (Node::Comment())
+    )
+}
+
+fn parse_template<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Vec<Node<'a>>> {
+    let res = many0!(i, alt!(
+        call!(take_content, s) |
+        call!(block_comment, s) |
+        call!(expr_node, s) |
+        call!(block_node, s)
+    ));
+    if let IResult::Done(left, _) = res {
+        reached(left);
+    }
+    res
+}
+
+pub fn parse<'a>(file: &FileInfo<'a>, syntax: &Syntax)
+                 -> Result<Vec<Node<'a>>, CompileError> {
+    let src = file.source;
+    FURTHEST.with(|furthest| furthest.set(usize::max_value()));
+    match parse_template(src.as_bytes(), syntax) {
         IResult::Done(left, res) => {
             if !left.is_empty() {
-                let s = str::from_utf8(left).unwrap();
-                panic!("unable to parse template:\n\n{:?}", s);
+                let furthest = FURTHEST.with(|furthest| furthest.get());
+                let s = &src[src.len() - cmp::min(left.len(), furthest)..];
+                Err(CompileError::at("unable to parse template", file, s))
             } else {
-                res
+                Ok(res)
             }
         },
-        IResult::Error(err) => panic!("problems parsing template source: {}", err),
-        IResult::Incomplete(_) => panic!("parsing incomplete"),
+        IResult::Error(err) => {
+            Err(CompileError::new(format!("problems parsing template source: {}", err)))
+        },
+        IResult::Incomplete(_) => {
+            Err(CompileError::at("unexpected end of template", file, &src[src.len()..]))
+        },
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use compile_error::FileInfo;
+    use config::Syntax;
+
     fn check_ws_split(s: &str, res: &(&str, &str, &str)) {
         let node = super::split_ws_parts(s.as_bytes());
         match node {
@@ -473,9 +1059,157 @@ mod tests {
         check_ws_split("b\n", &("", "b", "\n"));
         check_ws_split(" \t\r\n", &(" \t\r\n", "", ""));
     }
+    fn check_parse_error(src: &str, msg: &str) {
+        let file = FileInfo { path: None, source: src };
+        match super::parse(&file, &Syntax::default()) {
+            Ok(_) => panic!("expected parse error for {:?}", src),
+            Err(err) => assert_eq!(err.to_string(), msg),
+        }
+    }
     #[test]
-    #[should_panic]
     fn test_invalid_block() {
-        super::parse("{% extend \"blah\" %}");
+        check_parse_error("{% extend \"blah\" %}",
+                          "unable to parse template\n \
+                           --> <source>:1:1\n  \
+                           |\n\
+                           1 | {% extend \"blah\" %}\n  \
+                           | ^");
+    }
+    #[test]
+    fn test_error_location() {
+        check_parse_error("foo\n\tbar {{ a }} {% bar %}",
+                          "unable to parse template\n \
+                           --> <source>:2:14\n  \
+                           |\n\
+                           2 | \tbar {{ a }} {% bar %}\n  \
+                           | \t            ^");
+    }
+    #[test]
+    fn test_invalid_escape() {
+        check_parse_error("{{ \"a\\qb\" }}",
+                          "unable to parse template\n \
+                           --> <source>:1:6\n  \
+                           |\n\
+                           1 | {{ \"a\\qb\" }}\n  \
+                           |      ^");
+        check_parse_error("{% if x %}\n  {{ \"a\\qb\" }}\n{% endif %}",
+                          "unable to parse template\n \
+                           --> <source>:2:8\n  \
+                           |\n\
+                           2 |   {{ \"a\\qb\" }}\n  \
+                           |        ^");
+    }
+    #[test]
+    fn test_nested_error_location() {
+        check_parse_error("{% for x in xs %}\n{{ x }}\n{% if x.ok %}\n  {{ x.foo( }}\n{% endif %}\n\
+                           {% endfor %}",
+                          "unable to parse template\n \
+                           --> <source>:4:11\n  \
+                           |\n\
+                           4 |   {{ x.foo( }}\n  \
+                           |           ^");
+    }
+    #[test]
+    fn test_cut_off_after_separator() {
+        for src in &["{{ a.", "{{ a|", "{{ a::", "{% call f::", "{% import \"x\" as"] {
+            check_parse_error(src, &format!("unexpected end of template\n \
+                                             --> <source>:1:{}\n  \
+                                             |\n\
+                                             1 | {}\n  \
+                                             | {}^", src.len() + 1, src,
+                                            " ".repeat(src.len())));
+        }
+        check_parse_error("{% include \"a\" with {",
+                          "unable to parse template\n \
+                           --> <source>:1:1\n  \
+                           |\n\
+                           1 | {% include \"a\" with {\n  \
+                           | ^");
+    }
+    fn check_expr(src: &str, expected: &str) {
+        fn fmt(expr: &super::Expr) -> String {
+            match *expr {
+                super::Expr::NumLit(s) | super::Expr::Var(s) => s.to_string(),
+                super::Expr::BinOp(op, ref left, ref right) =>
+                    format!("({} {} {})", fmt(left), op, fmt(right)),
+                super::Expr::Call(ref path, ref args) => format!("{}({})", path.join("::"),
+                    args.iter().map(fmt).collect::<Vec<_>>().join(", ")),
+                super::Expr::NamedArg(name, ref value) => format!("{} = {}", name, fmt(value)),
+                _ => panic!("unexpected expression {:?}", expr),
+            }
+        }
+        let file = FileInfo { path: None, source: src };
+        match super::parse(&file, &Syntax::default()).unwrap()[0] {
+            super::Node::Expr(_, ref expr) => assert_eq!(fmt(expr), expected),
+            ref node => panic!("expected expression, got {:?}", node),
+        }
+    }
+    #[test]
+    fn test_associativity() {
+        check_expr("{{ 10 - 2 - 3 }}", "((10 - 2) - 3)");
+        check_expr("{{ a / b * c % d }}", "(((a / b) * c) % d)");
+        check_expr("{{ a || b || c }}", "((a || b) || c)");
+    }
+    #[test]
+    fn test_precedence() {
+        check_expr("{{ a + b * c - d }}", "((a + (b * c)) - d)");
+        check_expr("{{ a == b && c }}", "((a == b) && c)");
+        check_expr("{{ a && b == c || d }}", "((a && (b == c)) || d)");
+    }
+    #[test]
+    fn test_named_argument() {
+        check_expr("{{ f(a = b, c == d) }}", "f(a = b, (c == d))");
+        check_expr("{{ f(a=b+1) }}", "f(a = (b + 1))");
+    }
+    #[test]
+    fn test_many_calls() {
+        // Calls without a body must not make the parser look for one
+        let src = "{% call m() %}".repeat(100);
+        let file = FileInfo { path: None, source: &src };
+        assert_eq!(super::parse(&file, &Syntax::default()).unwrap().len(), 100);
+    }
+    #[test]
+    fn test_call_in_callblock() {
+        let src = "{% callblock a() %}{% call b() %}{% endcallblock %}";
+        let file = FileInfo { path: None, source: src };
+        match super::parse(&file, &Syntax::default()).unwrap()[0] {
+            super::Node::Call(_, _, "a", _, Some(ref body)) => match body.nodes[0] {
+                super::Node::Call(_, _, "b", _, None) => {},
+                ref node => panic!("expected call without a body, got {:?}", node),
+            },
+            ref node => panic!("expected callblock, got {:?}", node),
+        }
+    }
+    #[test]
+    fn test_binding_patterns() {
+        for src in &["{% let Foo = 1 %}", "{% let a::b = 1 %}", "{% let 1 = x %}",
+                     "{% for (a, \"b\") in xs %}{% endfor %}",
+                     "{% for Point { x: 0, y } in xs %}{% endfor %}"] {
+            let file = FileInfo { path: None, source: src };
+            assert!(super::parse(&file, &Syntax::default()).is_err(), "{:?} parsed", src);
+        }
+        for src in &["{% let (a, _) = x %}", "{% let Point { x, y: (a, b) } = p %}",
+                     "{% for Some(x) in xs %}{% endfor %}"] {
+            let file = FileInfo { path: None, source: src };
+            assert!(super::parse(&file, &Syntax::default()).is_ok(), "{:?} failed", src);
+        }
+    }
+    #[test]
+    fn test_include_context() {
+        let src = "{% include \"row.html\" ignore missing with { item: x.y, compact } %}";
+        let file = FileInfo { path: None, source: src };
+        match super::parse(&file, &Syntax::default()).unwrap()[0] {
+            super::Node::Include(_, path, ignore_missing, Some(ref context)) => {
+                assert_eq!(path, "\"row.html\"");
+                assert!(ignore_missing);
+                let names = context.iter().map(|&(name, _)| name).collect::<Vec<_>>();
+                assert_eq!(names, vec!["item", "compact"]);
+                match context[1].1 {
+                    super::Expr::Var(name) => assert_eq!(name, "compact"),
+                    ref expr => panic!("expected variable, got {:?}", expr),
+                }
+            },
+            ref node => panic!("expected include, got {:?}", node),
+        }
     }
 }
@@ -1,85 +1,136 @@
-use std::env;
+use compile_error::CompileError;
+use config::Config;
+
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 
-pub fn get_template_source(tpl_path: &Path) -> String {
-    let mut path = template_dir();
-    path.push(tpl_path);
-    let mut f = match File::open(&path) {
-        Err(_) => {
-            let msg = format!("unable to open template file '{}'",
-                              &path.to_str().unwrap());
-            panic!(msg)
-        },
-        Ok(f) => f,
-    };
+pub fn get_template_source(tpl_path: &Path) -> Result<String, CompileError> {
     let mut s = String::new();
-    f.read_to_string(&mut s).unwrap();
+    File::open(tpl_path)
+        .and_then(|mut f| f.read_to_string(&mut s))
+        .map_err(|err| CompileError::new(format!(
+            "unable to read template file '{}': {}", tpl_path.display(), err)))?;
     if s.ends_with('\n') {
         let _ = s.pop();
     }
-    s
+    Ok(s)
 }
 
-pub fn find_template_from_path(path: &str, start_at: Option<&Path>) -> PathBuf {
-    let root = template_dir();
+// Looks up a template in each of the template directories in order, and
+// then relative to the template at `start_at`. The first match wins, so a
+// directory listed earlier can override templates used from later ones.
+pub fn find_template_from_path(path: &str, start_at: Option<&Path>,
+                               dirs: &[PathBuf]) -> Result<PathBuf, CompileError> {
+    let mut searched = Vec::new();
+    for dir in dirs {
+        let fs_abs_path = dir.join(path);
+        if fs_abs_path.exists() {
+            return Ok(fs_abs_path);
+        }
+        searched.push(dir.as_path());
+    }
+
     if let Some(rel) = start_at {
-        let mut fs_rel_path = root.clone();
-        fs_rel_path.push(rel);
-        fs_rel_path = fs_rel_path.with_file_name(path);
+        let fs_rel_path = rel.with_file_name(path);
         if fs_rel_path.exists() {
-            return fs_rel_path.strip_prefix(&root).unwrap().to_owned();
+            return Ok(fs_rel_path);
+        }
+        if let Some(parent) = rel.parent() {
+            if !searched.contains(&parent) {
+                searched.push(parent);
+            }
         }
     }
 
-    let mut fs_abs_path = root.clone();
-    let path = Path::new(path);
-    fs_abs_path.push(Path::new(path));
-    if fs_abs_path.exists() {
-        path.to_owned()
-    } else {
-        panic!(format!("template '{:?}' not found", path.to_str()));
+    let mut msg = format!("template '{}' not found, searched in:", path);
+    for dir in searched {
+        msg.push_str(&format!("\n  {}", dir.display()));
+    }
+    Err(CompileError::new(msg))
+}
+
+/// Returns the template directories configured for the current crate
+pub fn template_dirs() -> Vec<PathBuf> {
+    match Config::new() {
+        Ok(config) => config.dirs,
+        Err(err) => panic!("{}", err),
     }
 }
 
+/// Returns the first template directory configured for the current crate
+#[deprecated(note = "templates can be spread over several directories, \
+                     use `template_dirs()` instead")]
 pub fn template_dir() -> PathBuf {
-    let mut path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
-    path.push("templates");
-    path
+    template_dirs().remove(0)
 }
 
 #[cfg(test)]
 mod tests {
     use super::{find_template_from_path, get_template_source};
-    use super::Path;
+    use super::PathBuf;
+    use std::env;
+
+    fn dirs() -> Vec<PathBuf> {
+        let mut path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+        path.push("templates");
+        vec![path.join("sub"), path]
+    }
+
+    fn root() -> PathBuf {
+        dirs().pop().unwrap()
+    }
+
+    fn find(path: &str, start_at: &str) -> PathBuf {
+        let start_at = root().join(start_at);
+        find_template_from_path(path, Some(&start_at), &dirs()).unwrap()
+    }
 
     #[test]
     fn get_source() {
-        assert_eq!(get_template_source(Path::new("sub/b.html")), "bar");
+        assert_eq!(get_template_source(&root().join("sub/b.html")).unwrap(), "bar");
     }
 
     #[test]
     fn find_absolute() {
-        let path = find_template_from_path("sub/b.html", Some(Path::new("a.html")));
-        assert_eq!(path, Path::new("sub/b.html"));
+        assert_eq!(find("sub/b.html", "a.html"), root().join("sub/b.html"));
     }
 
     #[test]
-    #[should_panic]
     fn find_relative_nonexistent() {
-        find_template_from_path("b.html", Some(Path::new("a.html")));
+        let start_at = root().join("a.html");
+        let err = find_template_from_path("d.html", Some(&start_at), &dirs()).unwrap_err();
+        assert_eq!(err.to_string(), format!(
+            "template 'd.html' not found, searched in:\n  {}\n  {}",
+            root().join("sub").display(), root().display()));
     }
 
     #[test]
     fn find_relative() {
-        let path = find_template_from_path("c.html", Some(Path::new("sub/b.html")));
-        assert_eq!(path, Path::new("sub/c.html"));
+        assert_eq!(find("c.html", "sub/b.html"), root().join("sub/c.html"));
     }
 
     #[test]
     fn find_relative_sub() {
-        let path = find_template_from_path("sub1/d.html", Some(Path::new("sub/b.html")));
-        assert_eq!(path, Path::new("sub/sub1/d.html"));
+        assert_eq!(find("sub1/d.html", "sub/b.html"), root().join("sub/sub1/d.html"));
+    }
+
+    #[test]
+    fn find_relative_only() {
+        // Not in any of the directories, only next to `d.html`
+        assert_eq!(find("f.html", "sub/sub1/d.html"), root().join("sub/sub1/f.html"));
+    }
+
+    #[test]
+    fn find_override() {
+        // Found in the first directory before the one next to `a.html`
+        assert_eq!(find("e.html", "a.html"), root().join("sub/e.html"));
+    }
+
+    #[test]
+    fn find_fallback() {
+        // Not next to `a.html`, found in the first directory instead
+        assert_eq!(find("c.html", "a.html"), root().join("sub/c.html"));
+        assert_eq!(find("a.html", "sub/b.html"), root().join("a.html"));
     }
 }
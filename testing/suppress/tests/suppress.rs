@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate askama;
+
+use askama::Template;
+
+
+#[derive(Template)]
+#[template(path = "list.html")]
+struct ListTemplate<'a> {
+    items: Vec<&'a str>,
+}
+
+#[test]
+fn test_suppress() {
+    let t = ListTemplate { items: vec!["foo", "bar"] };
+    assert_eq!(t.render().unwrap(), "<ul><li>foo!</li><li>bar!</li></ul>");
+}
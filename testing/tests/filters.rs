@@ -24,6 +24,20 @@ fn filter_escape() {
 }
 
 
+// The `svg` extension is mapped to HTML escaping in askama.toml
+#[derive(Template)]
+#[template(source = "<text>{{ s }}</text>", ext = "svg")]
+struct ConfigEscapeTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn filter_escape_config() {
+    let t = ConfigEscapeTemplate { s: "a < b" };
+    assert_eq!(t.render().unwrap(), "<text>a &lt; b</text>");
+}
+
+
 #[derive(Template)]
 #[template(path = "format.html", escape = "none")]
 struct FormatTemplate<'a> {
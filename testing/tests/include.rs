@@ -17,3 +17,72 @@ fn test_include() {
     };
     assert_eq!(s.render().unwrap(), "INCLUDED: fooINCLUDED: bar")
 }
+
+
+#[derive(Template)]
+#[template(path = "uses-shared.html")]
+struct SharedIncludeTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn test_include_fallback_dir() {
+    let t = SharedIncludeTemplate { s: "foo" };
+    assert_eq!(t.render().unwrap(), "uses shared: foo");
+}
+
+
+#[derive(Template)]
+#[template(path = "hello.html", dirs("templates-override", "templates"))]
+struct OverrideTemplate<'a> {
+    name: &'a str,
+}
+
+#[test]
+fn test_dirs_attribute() {
+    let t = OverrideTemplate { name: "world" };
+    assert_eq!(t.render().unwrap(), "Hi, world!");
+}
+
+
+#[derive(Template)]
+#[template(path = "include-context.html")]
+struct IncludeContextTemplate<'a> {
+    title: &'a str,
+    strs: &'a [&'a str],
+}
+
+#[test]
+fn test_include_context() {
+    let strs = vec!["foo", "bar"];
+    let t = IncludeContextTemplate { title: "item", strs: &strs };
+    assert_eq!(t.render().unwrap(), "item: foo, item: bar\n");
+}
+
+
+#[derive(Template)]
+#[template(path = "include-ws.html")]
+struct IncludeWsTemplate<'a> {
+    strs: &'a [&'a str],
+}
+
+#[test]
+fn test_include_ws() {
+    let strs = vec!["foo"];
+    let t = IncludeWsTemplate { strs: &strs };
+    assert_eq!(t.render().unwrap(), "[ INCLUDED: foo ] [INCLUDED: foo]\n");
+}
+
+
+#[derive(Template)]
+#[template(path = "shared-page.html", dirs("templates-override", "templates-shared"))]
+struct OverrideIncludeTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn test_dirs_override_include() {
+    // The override directory wins over the file next to `shared-page.html`
+    let t = OverrideIncludeTemplate { s: "foo" };
+    assert_eq!(t.render().unwrap(), "page: override: foo");
+}
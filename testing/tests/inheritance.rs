@@ -15,6 +15,24 @@ struct ChildTemplate<'a> {
     _parent: BaseTemplate<'a>,
 }
 
+#[derive(Template)]
+#[template(path = "child.html")]
+struct FlatChildTemplate<'a> {
+    title: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "child.html", parent = "base")]
+struct NamedParentTemplate<'a> {
+    base: BaseTemplate<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "grandchild.html")]
+struct GrandChildTemplate<'a> {
+    _parent: ChildTemplate<'a>,
+}
+
 #[test]
 fn test_use_base_directly() {
     let t = BaseTemplate { title: "Foo" };
@@ -26,3 +44,59 @@ fn test_simple_extends() {
     let t = ChildTemplate { _parent: BaseTemplate { title: "Bar" } };
     assert_eq!(t.render().unwrap(), "Bar\n(Bar) Content goes here\nFoo\nCopyright 2017");
 }
+
+#[test]
+fn test_extends_without_parent_field() {
+    let t = FlatChildTemplate { title: "Flat" };
+    assert_eq!(t.render().unwrap(), "Flat\n(Flat) Content goes here\nFoo\nCopyright 2017");
+}
+
+#[test]
+fn test_extends_with_named_parent_field() {
+    let t = NamedParentTemplate { base: BaseTemplate { title: "Qux" } };
+    assert_eq!(t.render().unwrap(), "Qux\n(Qux) Content goes here\nFoo\nCopyright 2017");
+}
+
+#[test]
+fn test_deep_extends_with_super() {
+    let base = BaseTemplate { title: "Baz" };
+    let t = GrandChildTemplate { _parent: ChildTemplate { _parent: base } };
+    assert_eq!(t.render().unwrap(),
+               "Baz\n[(Baz) Content goes here]\nFoo and Bar\nCopyright 2017");
+}
+
+#[derive(Template)]
+#[template(path = "nested-base.html")]
+struct NestedBaseTemplate {
+    show: bool,
+    items: Vec<u32>,
+}
+
+#[derive(Template)]
+#[template(path = "nested-child.html")]
+struct NestedChildTemplate {
+    _parent: NestedBaseTemplate,
+}
+
+#[derive(Template)]
+#[template(path = "nested-super.html")]
+struct NestedSuperTemplate {
+    _parent: NestedBaseTemplate,
+}
+
+#[test]
+fn test_nested_blocks() {
+    let t = NestedBaseTemplate { show: true, items: vec![1, 2] };
+    assert_eq!(t.render().unwrap(), "[sidebar]extra\n12");
+}
+
+#[test]
+fn test_override_nested_blocks() {
+    let base = NestedBaseTemplate { show: false, items: vec![1, 2] };
+    let t = NestedChildTemplate { _parent: base };
+    assert_eq!(t.render().unwrap(), "[side]\n<1><2>");
+
+    let base = NestedBaseTemplate { show: true, items: vec![3] };
+    let t = NestedSuperTemplate { _parent: base };
+    assert_eq!(t.render().unwrap(), "[sidebar]more!\n3");
+}
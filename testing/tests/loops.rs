@@ -33,3 +33,156 @@ fn test_nested_for() {
     };
     assert_eq!(s.render().unwrap(), "1\n  0a1b2c2\n  0one1two");
 }
+
+
+struct Point {
+    x: u32,
+    y: u32,
+}
+
+#[derive(Template)]
+#[template(path = "for-destructure.html")]
+struct DestructureForTemplate<'a> {
+    pairs: Vec<(&'a str, (usize, &'a str))>,
+    points: Vec<Point>,
+}
+
+#[test]
+fn test_destructure_for() {
+    let s = DestructureForTemplate {
+        pairs: vec![("apples", (3, "left")), ("pears", (0, "gone"))],
+        points: vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }],
+    };
+    assert_eq!(s.render().unwrap(), "apples: 3 left\npears: 0 gone\n(1, 2)(3, 4)");
+}
+
+
+#[derive(Template)]
+#[template(path = "for-loop-vars.html")]
+struct LoopVarsTemplate<'a> {
+    strings: Vec<&'a str>,
+}
+
+#[test]
+fn test_loop_vars() {
+    let s = LoopVarsTemplate {
+        strings: vec!["A", "b", "C"],
+    };
+    assert_eq!(s.render().unwrap(),
+               "[<odd 1/3 3 2>A, <even 2/3 2 1>b, <odd 3/3 1 0>C]");
+}
+
+
+#[derive(Template)]
+#[template(path = "for-else.html")]
+struct ForElseTemplate {
+    values: Vec<u32>,
+}
+
+#[test]
+fn test_for_else() {
+    let t = ForElseTemplate { values: vec![1, 2] };
+    assert_eq!(t.render().unwrap(), "12");
+    let t = ForElseTemplate { values: vec![] };
+    assert_eq!(t.render().unwrap(), "empty");
+}
+
+
+#[derive(Template)]
+#[template(path = "for-break.html")]
+struct ForBreakTemplate<'a> {
+    values: Vec<&'a str>,
+}
+
+#[test]
+fn test_for_break_continue() {
+    let t = ForBreakTemplate { values: vec!["a", "b", "c", "d", "e"] };
+    assert_eq!(t.render().unwrap(), "ac");
+}
+
+
+struct Item<'a> {
+    name: &'a str,
+    visible: bool,
+}
+
+#[derive(Template)]
+#[template(path = "for-if.html")]
+struct ForIfTemplate<'a> {
+    items: Vec<Item<'a>>,
+}
+
+#[test]
+fn test_for_if() {
+    let t = ForIfTemplate {
+        items: vec![
+            Item { name: "foo", visible: false },
+            Item { name: "bar", visible: true },
+            Item { name: "baz", visible: true },
+        ],
+    };
+    assert_eq!(t.render().unwrap(), "1/2: bar\n2/2: baz\n");
+
+    let t = ForIfTemplate { items: vec![Item { name: "foo", visible: false }] };
+    assert_eq!(t.render().unwrap(), "nothing visible\n");
+}
+
+#[derive(Template)]
+#[template(path = "for-if-num.html")]
+struct ForIfNumTemplate {
+    count: usize,
+    nums: Vec<i32>,
+}
+
+#[test]
+fn test_for_if_num() {
+    let t = ForIfNumTemplate { count: 4, nums: vec![1, 2, 3, 4] };
+    assert_eq!(t.render().unwrap(), "23\n24");
+}
+
+
+#[derive(Template)]
+#[template(path = "range.html")]
+struct RangeTemplate<'a> {
+    count: usize,
+    items: Vec<&'a str>,
+    s: &'a str,
+}
+
+#[test]
+fn test_range_and_index() {
+    let t = RangeTemplate { count: 3, items: vec!["a", "b", "c"], s: "abcd" };
+    assert_eq!(t.render().unwrap(), "012\n1,2,3\na c bc ab cd ab\n012\n01(0)2(01(0))");
+}
+
+
+struct TreeNode<'a> {
+    name: &'a str,
+    children: Vec<TreeNode<'a>>,
+}
+
+impl<'a> TreeNode<'a> {
+    fn new(name: &'a str, children: Vec<TreeNode<'a>>) -> TreeNode<'a> {
+        TreeNode { name, children }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "for-recursive.html")]
+struct RecursiveTemplate<'a> {
+    nodes: Vec<TreeNode<'a>>,
+}
+
+#[test]
+fn test_for_recursive() {
+    let t = RecursiveTemplate {
+        nodes: vec![
+            TreeNode::new("a", vec![
+                TreeNode::new("b", vec![TreeNode::new("c", vec![])]),
+                TreeNode::new("d", vec![]),
+            ]),
+            TreeNode::new("e", vec![]),
+        ],
+    };
+    assert_eq!(t.render().unwrap(), "0.1 a\n1.1 b\n2.1 c\n1.2 d\n0.2 e\n");
+}
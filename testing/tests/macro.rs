@@ -14,3 +14,75 @@ fn test_macro() {
     let t = MacroTemplate { s: "foo" };
     assert_eq!(t.render().unwrap(), "foo foo foo");
 }
+
+#[derive(Template)]
+#[template(path = "import.html")]
+struct ImportTemplate<'a> {
+    name: &'a str,
+}
+
+#[test]
+fn test_import() {
+    let t = ImportTemplate { name: "user" };
+    assert_eq!(t.render().unwrap(),
+               "<input name=\"q\">\n<label><input name=\"user\"></label>\n<a href=\"user\">");
+}
+
+#[derive(Template)]
+#[template(path = "macro-args.html")]
+struct MacroArgsTemplate<'a> {
+    label: &'a str,
+    kind: &'a str,
+}
+
+#[test]
+fn test_macro_args() {
+    let t = MacroArgsTemplate { label: "Go", kind: "link" };
+    assert_eq!(t.render().unwrap(),
+               "[primary:Save]\n[secondary:Cancel]\n[danger:Delete]\n[link:Go]\n<Go:NEW> <x:OLD>");
+}
+
+#[derive(Template)]
+#[template(path = "caller.html")]
+struct CallerTemplate<'a> {
+    name: &'a str,
+    nums: Vec<u32>,
+}
+
+#[test]
+fn test_caller() {
+    let t = CallerTemplate { name: "Ann", nums: vec![1, 2] };
+    assert_eq!(t.render().unwrap(),
+               "\n\n<div class=\"note\">Ann</div>\n\
+                <div class=\"row\">1</div><div class=\"row\">2</div>\n\
+                <1><2>\n\
+                <div class=\"outer\">inner</div>\n\
+                <div class=\"icon\">*</div>\n\
+                <div class=\"a\"><div class=\"b\">x</div></div>\n\
+                [1/21][1/22][2/21][2/22]");
+}
+
+#[derive(Template)]
+#[template(path = "macro-ws.html")]
+struct MacroWsTemplate;
+
+#[test]
+fn test_macro_call_ws() {
+    // The call tag's own whitespace markers apply to the text around it
+    let t = MacroWsTemplate;
+    assert_eq!(t.render().unwrap(), "a [x] b\na [x]b\na[x] b");
+}
+
+#[derive(Template)]
+#[template(path = "macro-shadow.html")]
+struct MacroShadowTemplate<'a> {
+    name: &'a str,
+    greeting: &'a str,
+}
+
+#[test]
+fn test_macro_args_shadow_fields() {
+    // Arguments are evaluated before the parameters take their names
+    let t = MacroShadowTemplate { name: "Ann", greeting: "Hello" };
+    assert_eq!(t.render().unwrap(), "Ann, Hello!");
+}
@@ -0,0 +1,67 @@
+#[macro_use]
+extern crate askama;
+
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "match-opt.html")]
+struct MatchOptTemplate<'a> {
+    item: Option<&'a str>,
+}
+
+#[test]
+fn test_match_option() {
+    let s = MatchOptTemplate { item: Some("foo") };
+    assert_eq!(s.render().unwrap(), "\n    Found literal foo\n  ");
+
+    let s = MatchOptTemplate { item: Some("bar") };
+    assert_eq!(s.render().unwrap(), "\n    Found bar\n  ");
+
+    let s = MatchOptTemplate { item: None };
+    assert_eq!(s.render().unwrap(), "\n    Not Found\n");
+}
+
+
+#[derive(Template)]
+#[template(path = "match-literal.html")]
+struct MatchLitTemplate<'a> {
+    item: &'a str,
+}
+
+#[test]
+fn test_match_literal() {
+    let s = MatchLitTemplate { item: "bar" };
+    assert_eq!(s.render().unwrap(), "Found literal bar");
+
+    let s = MatchLitTemplate { item: "qux" };
+    assert_eq!(s.render().unwrap(), "Else found qux");
+}
+
+
+#[allow(dead_code)]
+enum Color {
+    Rgb(u8, u8, u8),
+    Named { name: &'static str, dark: bool },
+    Transparent,
+}
+
+#[derive(Template)]
+#[template(path = "match-enum.html")]
+struct MatchEnumTemplate {
+    color: Color,
+}
+
+#[test]
+fn test_match_enum() {
+    let s = MatchEnumTemplate { color: Color::Rgb(1, 2, 3) };
+    assert_eq!(s.render().unwrap(), "rgb(1, 2, 3)");
+
+    let s = MatchEnumTemplate { color: Color::Named { name: "blue", dark: true } };
+    assert_eq!(s.render().unwrap(), "dark blue");
+
+    let s = MatchEnumTemplate { color: Color::Named { name: "red", dark: false } };
+    assert_eq!(s.render().unwrap(), "red");
+
+    let s = MatchEnumTemplate { color: Color::Transparent };
+    assert_eq!(s.render().unwrap(), "transparent");
+}
@@ -40,5 +40,19 @@ struct PrecedenceTemplate { }
 #[test]
 fn test_precedence() {
     let t = PrecedenceTemplate { };
-    assert_eq!(t.render().unwrap(), "6".repeat(7));
+    assert_eq!(t.render().unwrap(), "6".repeat(9));
+}
+
+
+#[derive(Template)]
+#[template(path = "unary.html")]
+struct UnaryTemplate {
+    cond: bool,
+    num: i32,
+}
+
+#[test]
+fn test_unary() {
+    let t = UnaryTemplate { cond: false, num: 5 };
+    assert_eq!(t.render().unwrap(), "not-5 3 false");
 }
@@ -77,6 +77,38 @@ fn test_literals() {
 }
 
 
+#[derive(Template)]
+#[template(path = "literals-more.html", escape = "none")]
+struct MoreLiteralsTemplate {
+    c: char,
+    num: i32,
+    cond: bool,
+}
+
+#[test]
+fn test_more_literals() {
+    let s = MoreLiteralsTemplate { c: '\n', num: -1, cond: true };
+    assert_eq!(s.render().unwrap(), "true false 3.14 c \' true float\nnewline\nminus one\nyes");
+}
+
+
+#[derive(Template)]
+#[template(path = "strings.html", escape = "none")]
+struct StringsTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn test_string_literals() {
+    let t = StringsTemplate { s: "it's" };
+    assert_eq!(t.render().unwrap(),
+               "|a \"quoted\" word|tab\tnewline\n|single \"double\" it's||\u{e9}A\n\
+                raw \\n|has \"quotes\"\n\
+                single\n\
+                INCLUDED: it's");
+}
+
+
 struct Holder {
     a: usize,
 }
@@ -154,3 +186,62 @@ fn test_composition() {
     let t = CompositionTemplate { foo: IfTemplate { cond: true } };
     assert_eq!(t.render().unwrap(), "composed: true");
 }
+
+
+#[derive(PartialEq)]
+enum Status {
+    Active,
+    #[allow(dead_code)]
+    Inactive,
+}
+
+fn double(n: u32) -> u32 {
+    n * 2
+}
+
+#[derive(Template)]
+#[template(path = "path-call.html")]
+struct PathCallTemplate {
+    status: Status,
+    count: u32,
+}
+
+#[test]
+fn test_path_call() {
+    let t = PathCallTemplate { status: Status::Active, count: 3 };
+    assert_eq!(t.render().unwrap(), "active 6 5");
+}
+
+
+#[derive(Template)]
+#[template(path = "collections.html")]
+struct CollectionsTemplate {}
+
+#[test]
+fn test_collection_literals() {
+    let t = CollectionsTemplate {};
+    assert_eq!(t.render().unwrap(), "SML\na=1 b=2 \nx3 3 4");
+}
+
+
+#[derive(Template)]
+#[template(path = "raw.html")]
+struct RawTemplate {}
+
+#[test]
+fn test_raw() {
+    let t = RawTemplate {};
+    assert_eq!(t.render().unwrap(), "{{ name }}{% if %}{# x #}|\n {{ a }} end");
+}
+
+
+#[derive(Template)]
+#[template(source = "a {# comment #} b\n{% macro m() %}{% endmacro %}\nc", ext = "txt")]
+struct CommentWsTemplate {}
+
+#[test]
+fn test_comment_ws() {
+    // Whitespace on both sides of tags without output is kept
+    let t = CommentWsTemplate {};
+    assert_eq!(t.render().unwrap(), "a  b\n\nc");
+}
@@ -0,0 +1,33 @@
+#[macro_use]
+extern crate askama;
+
+use askama::Template;
+
+
+#[derive(Template)]
+#[template(path = "syntax.tex", syntax = "latex")]
+struct LatexTemplate<'a> {
+    title: &'a str,
+    items: Vec<&'a str>,
+}
+
+#[test]
+fn test_custom_syntax() {
+    let t = LatexTemplate { title: "Fruit", items: vec!["apple", "pear"] };
+    assert_eq!(t.render().unwrap(), "\\section{Fruit}\n\\begin{itemize}\n\
+                                     \\item apple\n\\item pear\n\\end{itemize}");
+}
+
+
+#[derive(Template)]
+#[template(source = "{{ a }} \\VAR{- a -} {% raw %}\\VAR{ a }{% endraw %}",
+           ext = "txt", syntax = "default")]
+struct DefaultSyntaxTemplate {
+    a: u8,
+}
+
+#[test]
+fn test_default_syntax() {
+    let t = DefaultSyntaxTemplate { a: 1 };
+    assert_eq!(t.render().unwrap(), "1 \\VAR{- a -} \\VAR{ a }");
+}
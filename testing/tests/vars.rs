@@ -28,3 +28,16 @@ fn test_let_decl() {
     let t = LetDeclTemplate { cond: false, s: "bar" };
     assert_eq!(t.render().unwrap(), "bar");
 }
+
+
+#[derive(Template)]
+#[template(source = "{% let (a, (b, c)) = nested %}{{ a }}{{ b }}{{ c }}", ext = "txt")]
+struct LetTupleTemplate<'a> {
+    nested: (&'a str, (String, usize)),
+}
+
+#[test]
+fn test_let_tuple() {
+    let t = LetTupleTemplate { nested: ("foo", ("bar".to_string(), 42)) };
+    assert_eq!(t.render().unwrap(), "foobar42");
+}